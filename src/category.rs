@@ -0,0 +1,110 @@
+// Extension-to-category table used by `organize --by category`.
+
+use crate::classify::{Classifier, FileInfo};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Category for files whose extension isn't in the table.
+pub const OTHER: &str = "Other";
+
+const BUILTIN: &[(&str, &[&str])] = &[
+    (
+        "Images",
+        &[
+            "jpg", "jpeg", "png", "gif", "bmp", "tif", "tiff", "webp", "heic", "heif", "svg",
+            "ico", "raw", "cr2", "nef", "arw", "dng", "psd",
+        ],
+    ),
+    (
+        "Documents",
+        &[
+            "pdf", "doc", "docx", "odt", "rtf", "txt", "md", "xls", "xlsx", "ods", "csv", "ppt",
+            "pptx", "odp", "epub", "tex",
+        ],
+    ),
+    (
+        "Audio",
+        &[
+            "mp3", "wav", "flac", "aac", "ogg", "oga", "m4a", "wma", "opus", "aiff",
+        ],
+    ),
+    (
+        "Video",
+        &[
+            "mp4", "mkv", "avi", "mov", "wmv", "flv", "webm", "m4v", "mpeg", "mpg", "3gp",
+        ],
+    ),
+    (
+        "Archives",
+        &[
+            "zip", "tar", "gz", "tgz", "bz2", "xz", "7z", "rar", "zst", "iso", "dmg",
+        ],
+    ),
+    (
+        "Code",
+        &[
+            "rs", "py", "js", "ts", "jsx", "tsx", "c", "h", "cpp", "hpp", "cc", "java", "kt", "go",
+            "rb", "php", "sh", "swift", "cs", "html", "css", "scss", "json", "yaml", "yml", "toml",
+            "xml", "sql", "lua",
+        ],
+    ),
+];
+
+pub struct Categories {
+    /// Lowercased extension -> category name.
+    by_ext: HashMap<String, String>,
+}
+
+impl Categories {
+    pub fn builtin() -> Categories {
+        let mut by_ext = HashMap::new();
+        for (category, exts) in BUILTIN {
+            for ext in *exts {
+                by_ext.insert(ext.to_string(), category.to_string());
+            }
+        }
+        Categories { by_ext }
+    }
+
+    /// Map `exts` to `category`, replacing any previous mapping for them.
+    pub fn assign(&mut self, category: &str, exts: &[String]) {
+        for ext in exts {
+            let ext = ext.trim_start_matches('.').to_lowercase();
+            self.by_ext.insert(ext, category.to_string());
+        }
+    }
+
+    /// Category for a lowercased extension; `None` (no extension) is `Other`.
+    pub fn category_of(&self, ext: Option<&str>) -> &str {
+        ext.and_then(|e| self.by_ext.get(e))
+            .map(String::as_str)
+            .unwrap_or(OTHER)
+    }
+}
+
+impl Classifier for Categories {
+    fn classify(&self, file: &FileInfo) -> Option<PathBuf> {
+        Some(PathBuf::from(self.category_of(file.ext)))
+    }
+}
+
+/// Parse a `--category` value of the form `Name=ext1,ext2`.
+pub fn parse_assignment(value: &str) -> Result<(String, Vec<String>), String> {
+    let (name, exts) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=EXT[,EXT...], got {:?}", value))?;
+    let name = name.trim();
+    if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+        return Err(format!("invalid category name {:?}", name));
+    }
+    let exts: Vec<String> = exts
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(String::from)
+        .collect();
+    if exts.is_empty() {
+        return Err(format!("category {:?} lists no extensions", name));
+    }
+    Ok((name.to_string(), exts))
+}
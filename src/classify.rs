@@ -0,0 +1,146 @@
+// Deciding which subfolder of the organized root a file belongs in.
+//
+// A `Classifier` looks at one file and names a directory relative to the
+// organized root. The built-in groupings (extension, category, date, size,
+// regex, rules file) all implement it, and `Composite` chains several so
+// their answers nest, e.g. `Images/2024`.
+
+use crate::scan::SIZE_BUCKET_BOUNDS;
+use crate::vfs::{FileSystem, Metadata};
+use regex::Regex;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// What a classifier gets to look at for one file.
+pub struct FileInfo<'a> {
+    /// Filesystem the file is on, for classifiers that read its contents.
+    pub fs: &'a dyn FileSystem,
+    pub path: &'a Path,
+    /// Lowercased extension to classify by, from the name or the content.
+    pub ext: Option<&'a str>,
+    pub meta: &'a Metadata,
+    /// When the run started, for age-based decisions.
+    pub now: SystemTime,
+}
+
+pub trait Classifier {
+    /// Destination directory relative to the organized root, or `None` to
+    /// leave the file where it is.
+    fn classify(&self, file: &FileInfo) -> Option<PathBuf>;
+}
+
+/// One folder per lowercased extension; files without one go to `no_ext`.
+pub struct ByExtension;
+
+impl Classifier for ByExtension {
+    fn classify(&self, file: &FileInfo) -> Option<PathBuf> {
+        Some(PathBuf::from(file.ext.unwrap_or("no_ext")))
+    }
+}
+
+/// Folders by file size.
+pub struct SizeBuckets {
+    /// Exclusive upper bounds with their folder names, smallest first.
+    bounds: Vec<(u64, String)>,
+    /// Folder for files at or above the last bound.
+    largest: String,
+}
+
+impl SizeBuckets {
+    /// `bounds` are exclusive upper bounds with the folder for files below
+    /// each; files at or above all of them go to `largest`.
+    pub fn new(mut bounds: Vec<(u64, String)>, largest: &str) -> SizeBuckets {
+        bounds.sort_by_key(|(bound, _)| *bound);
+        SizeBuckets {
+            bounds,
+            largest: largest.to_string(),
+        }
+    }
+}
+
+impl Default for SizeBuckets {
+    /// The same buckets as the scan histogram: Tiny, Small, Medium, Large.
+    fn default() -> SizeBuckets {
+        let names = ["Tiny", "Small", "Medium"];
+        let bounds = SIZE_BUCKET_BOUNDS
+            .iter()
+            .zip(names)
+            .map(|(&bound, name)| (bound, name.to_string()))
+            .collect();
+        SizeBuckets::new(bounds, "Large")
+    }
+}
+
+impl Classifier for SizeBuckets {
+    fn classify(&self, file: &FileInfo) -> Option<PathBuf> {
+        let size = file.meta.len;
+        let name = self
+            .bounds
+            .iter()
+            .find(|(bound, _)| size < *bound)
+            .map_or(&self.largest, |(_, name)| name);
+        Some(PathBuf::from(name))
+    }
+}
+
+/// Matches a regex against the file name and builds the folder from its
+/// captures, e.g. `^IMG_(\d{4})` with `Photos/$1`. Files that don't match
+/// are left alone.
+pub struct ByRegex {
+    regex: Regex,
+    dest: String,
+}
+
+impl ByRegex {
+    pub fn new(pattern: &str, dest: &str) -> io::Result<ByRegex> {
+        let regex = Regex::new(pattern).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid regex {:?}: {}", pattern, e),
+            )
+        })?;
+        Ok(ByRegex {
+            regex,
+            dest: dest.to_string(),
+        })
+    }
+}
+
+impl Classifier for ByRegex {
+    fn classify(&self, file: &FileInfo) -> Option<PathBuf> {
+        let name = file.path.file_name()?.to_string_lossy();
+        let caps = self.regex.captures(&name)?;
+        let mut expanded = String::new();
+        caps.expand(&self.dest, &mut expanded);
+
+        // Captured text is untrusted; never let it climb out of the root.
+        let dir: PathBuf = Path::new(&expanded)
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect();
+        (!dir.as_os_str().is_empty()).then_some(dir)
+    }
+}
+
+/// Nests the folders of several classifiers, outermost first. If any of
+/// them leaves the file alone, so does the composite.
+pub struct Composite {
+    parts: Vec<Box<dyn Classifier>>,
+}
+
+impl Composite {
+    pub fn new(parts: Vec<Box<dyn Classifier>>) -> Composite {
+        Composite { parts }
+    }
+}
+
+impl Classifier for Composite {
+    fn classify(&self, file: &FileInfo) -> Option<PathBuf> {
+        let mut dir = PathBuf::new();
+        for part in &self.parts {
+            dir.push(part.classify(file)?);
+        }
+        Some(dir)
+    }
+}
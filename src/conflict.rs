@@ -0,0 +1,212 @@
+// What to do when a planned destination is already taken.
+
+use crate::mover::same_contents;
+use crate::vfs::FileSystem;
+use clap::ValueEnum;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnConflict {
+    /// Leave the source file where it is
+    Skip,
+    /// Move to a free name such as `name (1).ext`
+    #[default]
+    Rename,
+    /// Replace the existing file
+    Overwrite,
+    /// Replace the existing file only if the source is newer
+    KeepNewer,
+    /// Replace the existing file only if the source is larger
+    KeepLarger,
+    /// Delete the source if it is byte-identical to the existing file,
+    /// otherwise fall back to `rename`
+    DedupeIfIdentical,
+}
+
+#[derive(Debug)]
+pub enum Action {
+    /// Move to this (free) destination.
+    Move(PathBuf),
+    /// Move over the existing file at this destination.
+    Replace(PathBuf),
+    /// Leave the source alone.
+    Skip(&'static str),
+    /// The source duplicates the file at this destination; delete the source.
+    RemoveSource(PathBuf),
+}
+
+/// Decide what to do with `src` given its planned `dst`.
+///
+/// `occupant` reports which file's contents will be at a path when this move
+/// happens (`None` if the path is free). For a real run that's simply the
+/// path itself if it exists; a dry run also has to account for moves planned
+/// earlier in the same run.
+pub fn resolve(
+    fs: &dyn FileSystem,
+    src: &Path,
+    dst: &Path,
+    policy: OnConflict,
+    occupant: impl Fn(&Path) -> Option<PathBuf>,
+) -> io::Result<Action> {
+    let Some(existing) = occupant(dst) else {
+        return Ok(Action::Move(dst.to_path_buf()));
+    };
+
+    let action = match policy {
+        OnConflict::Skip => Action::Skip("destination exists"),
+        OnConflict::Rename => Action::Move(free_name(dst, &occupant)),
+        OnConflict::Overwrite => Action::Replace(dst.to_path_buf()),
+        OnConflict::KeepNewer => {
+            if fs.metadata(src)?.modified > fs.metadata(&existing)?.modified {
+                Action::Replace(dst.to_path_buf())
+            } else {
+                Action::Skip("destination is newer or the same age")
+            }
+        }
+        OnConflict::KeepLarger => {
+            if fs.metadata(src)?.len > fs.metadata(&existing)?.len {
+                Action::Replace(dst.to_path_buf())
+            } else {
+                Action::Skip("destination is larger or the same size")
+            }
+        }
+        OnConflict::DedupeIfIdentical => {
+            if same_contents(fs, src, &existing)? {
+                Action::RemoveSource(dst.to_path_buf())
+            } else {
+                Action::Move(free_name(dst, &occupant))
+            }
+        }
+    };
+    Ok(action)
+}
+
+/// First of `name (1).ext`, `name (2).ext`, ... that is free.
+fn free_name(dst: &Path, occupant: impl Fn(&Path) -> Option<PathBuf>) -> PathBuf {
+    let stem = dst
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let ext = dst.extension().map(|e| e.to_string_lossy().into_owned());
+
+    (1..)
+        .map(|n| {
+            let name = match &ext {
+                Some(ext) => format!("{} ({}).{}", stem, n, ext),
+                None => format!("{} ({})", stem, n),
+            };
+            dst.with_file_name(name)
+        })
+        .find(|candidate| occupant(candidate).is_none())
+        .expect("ran out of candidate names")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFs;
+    use std::time::{Duration, SystemTime};
+
+    /// Occupied paths are the ones that exist.
+    fn resolve_on(fs: &MemoryFs, policy: OnConflict) -> Action {
+        let occupant = |p: &Path| fs.symlink_metadata(p).is_ok().then(|| p.to_path_buf());
+        resolve(
+            fs,
+            Path::new("/in/a.txt"),
+            Path::new("/out/a.txt"),
+            policy,
+            occupant,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn free_names_count_up_past_taken_ones() {
+        let taken = ["/o/a.txt", "/o/a (1).txt", "/o/a (2).txt", "/o/README"];
+        let occupant = |p: &Path| {
+            taken
+                .iter()
+                .any(|t| p == Path::new(t))
+                .then(|| p.to_path_buf())
+        };
+
+        assert_eq!(
+            free_name(Path::new("/o/a.txt"), occupant),
+            Path::new("/o/a (3).txt")
+        );
+        assert_eq!(
+            free_name(Path::new("/o/README"), occupant),
+            Path::new("/o/README (1)")
+        );
+        assert_eq!(
+            free_name(Path::new("/o/b.tar.gz"), occupant),
+            Path::new("/o/b.tar (1).gz")
+        );
+    }
+
+    #[test]
+    fn a_free_destination_is_moved_to_whatever_the_policy() {
+        let fs = MemoryFs::new();
+        fs.write_file("/in/a.txt", "a").unwrap();
+        assert!(matches!(
+            resolve_on(&fs, OnConflict::Skip),
+            Action::Move(dst) if dst == Path::new("/out/a.txt")
+        ));
+    }
+
+    #[test]
+    fn keep_newer_replaces_only_an_older_file() {
+        let fs = MemoryFs::new();
+        fs.write_file("/in/a.txt", "new").unwrap();
+        fs.write_file("/out/a.txt", "old").unwrap();
+        let then = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        fs.set_modified("/out/a.txt", then).unwrap();
+        fs.set_modified("/in/a.txt", then + Duration::from_secs(60))
+            .unwrap();
+        assert!(matches!(
+            resolve_on(&fs, OnConflict::KeepNewer),
+            Action::Replace(_)
+        ));
+
+        fs.set_modified("/in/a.txt", then).unwrap();
+        assert!(matches!(
+            resolve_on(&fs, OnConflict::KeepNewer),
+            Action::Skip(_)
+        ));
+    }
+
+    #[test]
+    fn keep_larger_replaces_only_a_smaller_file() {
+        let fs = MemoryFs::new();
+        fs.write_file("/in/a.txt", "longer").unwrap();
+        fs.write_file("/out/a.txt", "short").unwrap();
+        assert!(matches!(
+            resolve_on(&fs, OnConflict::KeepLarger),
+            Action::Replace(_)
+        ));
+
+        fs.write_file("/out/a.txt", "longer").unwrap();
+        assert!(matches!(
+            resolve_on(&fs, OnConflict::KeepLarger),
+            Action::Skip(_)
+        ));
+    }
+
+    #[test]
+    fn dedupe_removes_identical_sources_and_renames_the_rest() {
+        let fs = MemoryFs::new();
+        fs.write_file("/in/a.txt", "same").unwrap();
+        fs.write_file("/out/a.txt", "same").unwrap();
+        assert!(matches!(
+            resolve_on(&fs, OnConflict::DedupeIfIdentical),
+            Action::RemoveSource(dst) if dst == Path::new("/out/a.txt")
+        ));
+
+        fs.write_file("/out/a.txt", "sane").unwrap();
+        assert!(matches!(
+            resolve_on(&fs, OnConflict::DedupeIfIdentical),
+            Action::Move(dst) if dst == Path::new("/out/a (1).txt")
+        ));
+    }
+}
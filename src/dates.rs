@@ -0,0 +1,204 @@
+// File dates for `organize --by date`.
+//
+// A date can come from the filesystem (modification or birth time) or from
+// inside the file (EXIF for photos, `/CreationDate` for PDFs). Sources are
+// tried in the order given and the first one that yields a date wins.
+
+use crate::classify::{Classifier, FileInfo};
+use crate::template::{Template, civil_date};
+use crate::vfs::{FileSystem, Metadata, ReadSeek};
+use clap::ValueEnum;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Placeholders available in a `--pattern`.
+pub const PLACEHOLDERS: &[&str] = &["year", "month", "day", "ext"];
+
+/// Pattern used when none is given: `2024/03`.
+pub const DEFAULT_PATTERN: &str = "{year}/{month:02}";
+
+/// Sources tried when none are given: the embedded date, then mtime.
+pub const DEFAULT_SOURCES: &[DateSource] = &[DateSource::Embedded, DateSource::Mtime];
+
+/// Folder for files none of the sources could date.
+pub const UNDATED: &str = "undated";
+
+/// How much of a PDF to search, from each end, for its creation date.
+const PDF_WINDOW: u64 = 256 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DateSource {
+    /// Date embedded in the file: EXIF capture time or PDF creation date
+    #[value(alias = "exif")]
+    Embedded,
+    /// Filesystem birth (creation) time, where supported
+    Btime,
+    /// Filesystem modification time
+    Mtime,
+}
+
+/// `(year, month, day)`
+pub type Date = (i64, u32, u32);
+
+pub struct DateGrouping {
+    pattern: Template,
+    sources: Vec<DateSource>,
+}
+
+impl DateGrouping {
+    pub fn new(pattern: &str, sources: Vec<DateSource>) -> io::Result<DateGrouping> {
+        Ok(DateGrouping {
+            pattern: Template::parse(pattern, PLACEHOLDERS)?,
+            sources,
+        })
+    }
+}
+
+impl Classifier for DateGrouping {
+    fn classify(&self, file: &FileInfo) -> Option<PathBuf> {
+        let Some((year, month, day)) = file_date(file.fs, file.path, file.meta, &self.sources)
+        else {
+            return Some(PathBuf::from(UNDATED));
+        };
+        Some(self.pattern.render(|field| match field {
+            "year" => year.to_string(),
+            "month" => month.to_string(),
+            "day" => day.to_string(),
+            "ext" => file.ext.unwrap_or("no_ext").to_string(),
+            _ => unreachable!("placeholder validated at parse time"),
+        }))
+    }
+}
+
+/// The first date any of `sources` yields for `path`.
+pub fn file_date(
+    fs: &dyn FileSystem,
+    path: &Path,
+    meta: &Metadata,
+    sources: &[DateSource],
+) -> Option<Date> {
+    sources.iter().find_map(|source| match source {
+        DateSource::Embedded => embedded_date(fs, path, meta.len),
+        DateSource::Btime => meta.created.map(civil_date),
+        DateSource::Mtime => meta.modified.map(civil_date),
+    })
+}
+
+/// Date recorded inside the file. Unreadable or unrecognised files simply
+/// have none; that's not an error, the next source gets a turn.
+fn embedded_date(fs: &dyn FileSystem, path: &Path, len: u64) -> Option<Date> {
+    let mut file = fs.open(path).ok()?;
+    let mut magic = [0u8; 5];
+    let is_pdf = file.read_exact(&mut magic).is_ok() && &magic == b"%PDF-";
+    file.rewind().ok()?;
+
+    if is_pdf {
+        pdf_date(&mut *file, len)
+    } else {
+        exif_date(file)
+    }
+}
+
+fn exif_date(file: Box<dyn ReadSeek>) -> Option<Date> {
+    use exif::{In, Tag, Value};
+
+    let exif = exif::Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()?;
+    [Tag::DateTimeOriginal, Tag::DateTimeDigitized, Tag::DateTime]
+        .into_iter()
+        .find_map(|tag| {
+            let field = exif.get_field(tag, In::PRIMARY)?;
+            let Value::Ascii(ref values) = field.value else {
+                return None;
+            };
+            let dt = exif::DateTime::from_ascii(values.first()?).ok()?;
+            Some((i64::from(dt.year), u32::from(dt.month), u32::from(dt.day)))
+        })
+}
+
+/// Look for `/CreationDate (D:YYYYMMDD...)` near either end of the file,
+/// where writers put the document information dictionary.
+fn pdf_date(file: &mut dyn ReadSeek, len: u64) -> Option<Date> {
+    let mut buf = Vec::new();
+    file.take(PDF_WINDOW).read_to_end(&mut buf).ok()?;
+    if len > PDF_WINDOW {
+        file.seek(SeekFrom::Start(
+            len.saturating_sub(PDF_WINDOW).max(PDF_WINDOW),
+        ))
+        .ok()?;
+        file.read_to_end(&mut buf).ok()?;
+    }
+
+    const KEY: &[u8] = b"/CreationDate";
+    buf.windows(KEY.len())
+        .enumerate()
+        .filter(|(_, w)| *w == KEY)
+        .find_map(|(i, _)| parse_pdf_date(&buf[i + KEY.len()..]))
+}
+
+fn parse_pdf_date(rest: &[u8]) -> Option<Date> {
+    let rest = rest.trim_ascii_start().strip_prefix(b"(")?;
+    let rest = rest.strip_prefix(b"D:").unwrap_or(rest);
+    let digits = rest.get(..8)?;
+    if !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let digits = std::str::from_utf8(digits).ok()?;
+    let year = digits[..4].parse().ok()?;
+    let month = digits[4..6].parse().ok()?;
+    let day = digits[6..8].parse().ok()?;
+    ((1..=12).contains(&month) && (1..=31).contains(&day)).then_some((year, month, day))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFs;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn pdf_dates_take_the_day_and_ignore_the_rest() {
+        assert_eq!(
+            parse_pdf_date(b" (D:20240315120000+01'00')"),
+            Some((2024, 3, 15))
+        );
+        assert_eq!(parse_pdf_date(b"(19991231)"), Some((1999, 12, 31)));
+        assert_eq!(parse_pdf_date(b"(D:2024)"), None);
+        assert_eq!(parse_pdf_date(b"(D:20241301)"), None);
+        assert_eq!(parse_pdf_date(b"(D:20240100)"), None);
+        assert_eq!(parse_pdf_date(b"<FEFF0044>"), None);
+    }
+
+    #[test]
+    fn pdf_creation_dates_are_found_near_either_end() {
+        let fs = MemoryFs::new();
+        let meta = |path: &str| fs.metadata(Path::new(path)).unwrap();
+        fs.write_file("/short.pdf", "%PDF-1.7\n<< /CreationDate (D:20230102) >>")
+            .unwrap();
+        let mut long = b"%PDF-1.4\n".to_vec();
+        long.resize(3 * PDF_WINDOW as usize, b' ');
+        long.extend_from_slice(b"<< /Title (x) /CreationDate (D:20220607) >>");
+        fs.write_file("/long.pdf", long).unwrap();
+
+        let date = |path: &str| embedded_date(&fs, Path::new(path), meta(path).len);
+        assert_eq!(date("/short.pdf"), Some((2023, 1, 2)));
+        assert_eq!(date("/long.pdf"), Some((2022, 6, 7)));
+    }
+
+    #[test]
+    fn sources_are_tried_in_order() {
+        let fs = MemoryFs::new();
+        fs.write_file("/notes.txt", "no date in here").unwrap();
+        fs.set_modified(
+            "/notes.txt",
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        )
+        .unwrap();
+        let meta = fs.metadata(Path::new("/notes.txt")).unwrap();
+        let date = |sources: &[DateSource]| file_date(&fs, Path::new("/notes.txt"), &meta, sources);
+
+        assert_eq!(date(DEFAULT_SOURCES), Some((2023, 11, 14)));
+        assert_eq!(date(&[DateSource::Embedded]), None);
+    }
+}
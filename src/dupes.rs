@@ -0,0 +1,434 @@
+// Duplicate detection for the `dupes` subcommand.
+//
+// Files are narrowed down in three passes so that most of them are never
+// read in full: same size, then same hash of the first `PARTIAL_LEN` bytes,
+// then same hash of the whole file.
+
+use crate::conflict::{self, Action, OnConflict};
+use crate::failure;
+use crate::mover;
+use crate::scan::{Scanner, WalkOptions};
+use crate::vfs::FileSystem;
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const PARTIAL_LEN: u64 = 16 * 1024;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum HashAlgo {
+    Blake3,
+    Sha256,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DupeAction {
+    /// Only list duplicate sets
+    Report,
+    /// Delete every copy except the one kept
+    DeleteExtras,
+    /// Replace extra copies with hard links to the kept file
+    Hardlink,
+    /// Replace extra copies with symlinks to the kept file
+    Symlink,
+    /// Move extra copies into the `--to` directory
+    MoveTo,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Keep {
+    /// Keep the least recently modified copy
+    Oldest,
+    /// Keep the most recently modified copy
+    Newest,
+    /// Keep the copy with the shortest path
+    ShortestPath,
+}
+
+pub struct DupeOptions {
+    pub hash: HashAlgo,
+    pub action: DupeAction,
+    /// Destination for `DupeAction::MoveTo`. Never searched for duplicates.
+    pub move_to: Option<PathBuf>,
+    pub keep: Keep,
+    /// Copies under this directory are kept in preference to all others.
+    /// Compared by real path, so it must exist.
+    pub prefer: Option<PathBuf>,
+    /// Files smaller than this are ignored (empty files are all "identical").
+    pub min_size: u64,
+    pub dry_run: bool,
+}
+
+/// What `run` found or did, as it happens.
+#[derive(Debug)]
+pub enum Event {
+    /// A set of identical files of `size` bytes each; `keep` is the copy
+    /// kept and `extras` follow as `Handled` or `Failed` events.
+    Set {
+        keep: PathBuf,
+        extras: usize,
+        size: u64,
+    },
+    /// The action was applied to an extra copy (or in a dry run, would be).
+    Handled { path: PathBuf },
+    /// The action failed on an extra copy.
+    Failed { path: PathBuf, error: io::Error },
+    /// A file couldn't be read to hash it, so it was left out.
+    Unreadable { path: PathBuf, error: io::Error },
+}
+
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub sets: usize,
+    /// Copies beyond the one kept in each set.
+    pub extras: usize,
+    /// Bytes the extra copies take up.
+    pub wasted: u64,
+    /// Extra copies the action failed on.
+    pub failed: usize,
+}
+
+struct Candidate {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Find duplicates under `folder` and apply `opts.action` to them, reporting
+/// each set and file to `on_event`. Biggest waste first.
+pub fn run(
+    fs: &dyn FileSystem,
+    folder: &Path,
+    opts: &DupeOptions,
+    mut on_event: impl FnMut(Event),
+) -> io::Result<Outcome> {
+    if opts.action == DupeAction::MoveTo {
+        move_to_dir(opts)?;
+    }
+    let prefer = opts
+        .prefer
+        .as_deref()
+        .map(|dir| fs.canonicalize(dir).map_err(failure::at(dir, "prefer")))
+        .transpose()?;
+    let mut by_size: HashMap<u64, Vec<Candidate>> = HashMap::new();
+    let mut seen = HashSet::new();
+    let mut scanner = Scanner::new(fs, folder, &WalkOptions::recursive())?;
+    // Copies already moved aside aren't duplicates to find again.
+    if let Some(dir) = &opts.move_to
+        && let Ok(real) = fs.canonicalize(dir)
+    {
+        scanner = scanner.pruning(real);
+    }
+    for entry in scanner {
+        let entry = entry?;
+        if !entry.meta.is_file() || entry.meta.len < opts.min_size {
+            continue;
+        }
+        // Hard links to one file share its storage; they aren't duplicates.
+        if let Some(id) = entry.meta.file_id
+            && !seen.insert(id)
+        {
+            continue;
+        }
+        by_size.entry(entry.meta.len).or_default().push(Candidate {
+            size: entry.meta.len,
+            modified: entry.meta.modified.unwrap_or(SystemTime::UNIX_EPOCH),
+            path: entry.path,
+        });
+    }
+
+    let mut sets = Vec::new();
+    for group in by_size.into_values().filter(|g| g.len() > 1) {
+        let size = group[0].size;
+        for group in split_by_hash(fs, group, opts.hash, Some(PARTIAL_LEN), &mut on_event) {
+            if size <= PARTIAL_LEN {
+                sets.push(group);
+            } else {
+                sets.extend(split_by_hash(fs, group, opts.hash, None, &mut on_event));
+            }
+        }
+    }
+    // Biggest waste first.
+    sets.sort_by_key(|set| std::cmp::Reverse(set[0].size * (set.len() as u64 - 1)));
+
+    let mut outcome = Outcome {
+        sets: sets.len(),
+        ..Outcome::default()
+    };
+    for set in &mut sets {
+        order_by_keep_policy(fs, set, opts.keep, prefer.as_deref());
+        let size = set[0].size;
+        outcome.wasted += size * (set.len() as u64 - 1);
+        outcome.extras += set.len() - 1;
+
+        let keeper = &set[0];
+        on_event(Event::Set {
+            keep: keeper.path.clone(),
+            extras: set.len() - 1,
+            size,
+        });
+        for extra in &set[1..] {
+            let path = extra.path.clone();
+            match apply(fs, opts, &keeper.path, &extra.path) {
+                Ok(()) => on_event(Event::Handled { path }),
+                Err(error) => {
+                    on_event(Event::Failed { path, error });
+                    outcome.failed += 1;
+                }
+            }
+        }
+    }
+    Ok(outcome)
+}
+
+/// Split `group` into sub-groups of two or more files with equal hashes.
+/// `limit` hashes only that many leading bytes. Unreadable files are
+/// reported and dropped.
+fn split_by_hash(
+    fs: &dyn FileSystem,
+    group: Vec<Candidate>,
+    algo: HashAlgo,
+    limit: Option<u64>,
+    on_event: &mut impl FnMut(Event),
+) -> Vec<Vec<Candidate>> {
+    let mut by_hash: HashMap<Vec<u8>, Vec<Candidate>> = HashMap::new();
+    for file in group {
+        match digest(fs, &file.path, algo, limit) {
+            Ok(hash) => by_hash.entry(hash).or_default().push(file),
+            Err(error) => on_event(Event::Unreadable {
+                path: file.path,
+                error,
+            }),
+        }
+    }
+    by_hash.into_values().filter(|g| g.len() > 1).collect()
+}
+
+fn digest(
+    fs: &dyn FileSystem,
+    path: &Path,
+    algo: HashAlgo,
+    limit: Option<u64>,
+) -> io::Result<Vec<u8>> {
+    let mut reader = fs.open(path)?.take(limit.unwrap_or(u64::MAX));
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut blake = blake3::Hasher::new();
+    let mut sha = Sha256::new();
+
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        match algo {
+            HashAlgo::Blake3 => {
+                blake.update(&buf[..n]);
+            }
+            HashAlgo::Sha256 => sha.update(&buf[..n]),
+        }
+    }
+
+    Ok(match algo {
+        HashAlgo::Blake3 => blake.finalize().as_bytes().to_vec(),
+        HashAlgo::Sha256 => sha.finalize().to_vec(),
+    })
+}
+
+/// Put the copy to keep first. `prefer` is a real path; copies whose real
+/// path is under it come before all others.
+fn order_by_keep_policy(
+    fs: &dyn FileSystem,
+    set: &mut [Candidate],
+    keep: Keep,
+    prefer: Option<&Path>,
+) {
+    set.sort_by(|a, b| {
+        match keep {
+            Keep::Oldest => a.modified.cmp(&b.modified),
+            Keep::Newest => b.modified.cmp(&a.modified),
+            Keep::ShortestPath => (a.path.components().count(), a.path.as_os_str().len())
+                .cmp(&(b.path.components().count(), b.path.as_os_str().len())),
+        }
+        .then_with(|| a.path.cmp(&b.path))
+    });
+    // A stable sort, so the policy's order holds within each side.
+    if let Some(dir) = prefer {
+        set.sort_by_cached_key(|c| {
+            !fs.canonicalize(&c.path)
+                .is_ok_and(|real| real.starts_with(dir))
+        });
+    }
+}
+
+impl DupeAction {
+    /// Short word printed next to each extra copy.
+    pub fn label(self) -> &'static str {
+        match self {
+            DupeAction::Report => "dup",
+            DupeAction::DeleteExtras => "delete",
+            DupeAction::Hardlink => "link",
+            DupeAction::Symlink => "symlink",
+            DupeAction::MoveTo => "move",
+        }
+    }
+}
+
+/// Apply the configured action to one extra copy.
+fn apply(fs: &dyn FileSystem, opts: &DupeOptions, keeper: &Path, extra: &Path) -> io::Result<()> {
+    if opts.dry_run {
+        return Ok(());
+    }
+
+    match opts.action {
+        DupeAction::Report => Ok(()),
+        DupeAction::DeleteExtras => fs.remove_file(extra),
+        DupeAction::Hardlink => replace_with(fs, extra, |tmp| fs.hard_link(keeper, tmp)),
+        DupeAction::Symlink => {
+            let target = fs.canonicalize(keeper)?;
+            replace_with(fs, extra, |tmp| fs.symlink_file(&target, tmp))
+        }
+        DupeAction::MoveTo => {
+            let dir = move_to_dir(opts)?;
+            let dst = dir.join(extra.file_name().unwrap_or_default());
+            let occupant = |p: &Path| fs.symlink_metadata(p).is_ok().then(|| p.to_path_buf());
+            let Action::Move(dst) =
+                conflict::resolve(fs, extra, &dst, OnConflict::Rename, occupant)?
+            else {
+                unreachable!("rename always yields a free destination");
+            };
+            fs.create_dir_all(dir)?;
+            mover::move_file(fs, extra, &dst, false).map(|_| ())
+        }
+    }
+}
+
+/// Where `DupeAction::MoveTo` puts extra copies.
+fn move_to_dir(opts: &DupeOptions) -> io::Result<&Path> {
+    opts.move_to.as_deref().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "moving duplicates needs a directory to move them to",
+        )
+    })
+}
+
+/// Create a replacement for `path` at a temporary name, then rename it over
+/// `path`, so the original is never missing if something fails.
+fn replace_with(
+    fs: &dyn FileSystem,
+    path: &Path,
+    create: impl FnOnce(&Path) -> io::Result<()>,
+) -> io::Result<()> {
+    let tmp = mover::partial_path(path);
+    create(&tmp)?;
+    fs.rename(&tmp, path).inspect_err(|_| {
+        let _ = fs.remove_file(&tmp);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFs;
+    use std::time::Duration;
+
+    fn options(keep: Keep) -> DupeOptions {
+        DupeOptions {
+            hash: HashAlgo::Blake3,
+            action: DupeAction::Report,
+            move_to: None,
+            keep,
+            prefer: None,
+            min_size: 1,
+            dry_run: false,
+        }
+    }
+
+    /// Three copies of one file, oldest first, and an unrelated file.
+    fn copies() -> MemoryFs {
+        let fs = MemoryFs::new();
+        for (n, path) in ["/d/b/deep/x", "/d/c/x", "/d/a/x"].into_iter().enumerate() {
+            fs.write_file(path, "same").unwrap();
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1000 * (n as u64 + 1));
+            fs.set_modified(path, time).unwrap();
+        }
+        fs.write_file("/d/other", "diff").unwrap();
+        fs
+    }
+
+    /// The copy kept in each set found.
+    fn kept(fs: &MemoryFs, opts: &DupeOptions) -> Vec<PathBuf> {
+        let mut kept = Vec::new();
+        run(fs, Path::new("/d"), opts, |event| {
+            if let Event::Set { keep, .. } = event {
+                kept.push(keep);
+            }
+        })
+        .unwrap();
+        kept
+    }
+
+    #[test]
+    fn keep_policies_pick_the_copy_to_keep() {
+        let fs = copies();
+        assert_eq!(
+            kept(&fs, &options(Keep::Oldest)),
+            [PathBuf::from("/d/b/deep/x")]
+        );
+        assert_eq!(kept(&fs, &options(Keep::Newest)), [PathBuf::from("/d/a/x")]);
+        assert_eq!(
+            kept(&fs, &options(Keep::ShortestPath)),
+            [PathBuf::from("/d/a/x")]
+        );
+
+        let outcome = run(&fs, Path::new("/d"), &options(Keep::Oldest), |_| {}).unwrap();
+        assert_eq!((outcome.sets, outcome.extras, outcome.wasted), (1, 2, 8));
+    }
+
+    #[test]
+    fn a_preferred_folder_wins_by_real_path() {
+        let fs = copies();
+        fs.symlink("/d/c", "/favourite").unwrap();
+        let opts = DupeOptions {
+            prefer: Some(PathBuf::from("/favourite")),
+            ..options(Keep::Newest)
+        };
+        assert_eq!(kept(&fs, &opts), [PathBuf::from("/d/c/x")]);
+
+        let opts = DupeOptions {
+            prefer: Some(PathBuf::from("/nowhere")),
+            ..options(Keep::Newest)
+        };
+        assert!(run(&fs, Path::new("/d"), &opts, |_| {}).is_err());
+    }
+
+    #[test]
+    fn moved_copies_are_not_found_again() {
+        let fs = copies();
+        let opts = DupeOptions {
+            action: DupeAction::MoveTo,
+            move_to: Some(PathBuf::from("/d/dups")),
+            ..options(Keep::Oldest)
+        };
+        let outcome = run(&fs, Path::new("/d"), &opts, |_| {}).unwrap();
+        assert_eq!((outcome.extras, outcome.failed), (2, 0));
+        assert_eq!(fs.read_dir(Path::new("/d/dups")).unwrap().len(), 2);
+
+        let outcome = run(&fs, Path::new("/d"), &opts, |_| {}).unwrap();
+        assert_eq!(outcome.sets, 0);
+    }
+
+    #[test]
+    fn moving_copies_needs_somewhere_to_put_them() {
+        let fs = copies();
+        let opts = DupeOptions {
+            action: DupeAction::MoveTo,
+            ..options(Keep::Oldest)
+        };
+        let err = run(&fs, Path::new("/d"), &opts, |_| {}).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,815 @@
+// Carrying out a `Plan`.
+//
+// Each move has its conflict resolved against the destination as it is at
+// that moment, then happens and is journaled so `undo` can reverse it. A dry
+// run resolves conflicts against the files already there plus the moves
+// planned before it, so it reports what a real run would do.
+//
+// In the modes that copy or link (`Mode`), the originals stay put: a
+// destination that already is the copy or link is skipped, and
+// `dedupe-if-identical` skips instead of deleting the source.
+//
+// With `prune_below`, source directories that a run of moves leaves empty
+// are removed afterwards, up to but not including that folder.
+//
+// With `keep_going`, a move that fails is noted and the rest still happen.
+// Only errors about the file being moved count; if the journal can't be
+// written the run stops, since `undo` would lose track of it.
+//
+// With `atomic`, any failure puts everything back instead: completed moves
+// are reversed latest first and the directories the run created are removed.
+// Files that would be gone for good (ones replaced by `overwrite`, duplicates
+// removed by `skip-identical`) are only set aside until the run has finished.
+
+use crate::conflict::{self, Action, OnConflict};
+use crate::failure::{self, Failure};
+use crate::journal::{self, JOURNAL_FILE, Journal};
+use crate::mover::{self, Mode, Moved};
+use crate::plan::{Plan, PlannedMove};
+use crate::vfs::FileSystem;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub struct Executor {
+    pub mode: Mode,
+    pub on_conflict: OnConflict,
+    /// Verify cross-filesystem copies before deleting the source.
+    pub verify: bool,
+    pub dry_run: bool,
+    /// Note moves that fail in `Outcome::failures` and carry on.
+    pub keep_going: bool,
+    /// On any failure, undo the whole run.
+    pub atomic: bool,
+    /// Remove directories under this folder that moves leave empty.
+    pub prune_below: Option<PathBuf>,
+}
+
+/// What happened (or in a dry run, would happen) to one planned move.
+#[derive(Debug)]
+pub enum Event {
+    /// `src` went to `dst`, or was copied or linked there. `replaced` is set
+    /// when it went over an existing file.
+    Moved {
+        src: PathBuf,
+        dst: PathBuf,
+        replaced: bool,
+        how: Moved,
+    },
+    Skipped {
+        src: PathBuf,
+        reason: &'static str,
+    },
+    /// `src` was deleted because it was identical to `duplicate_of`.
+    Removed {
+        src: PathBuf,
+        duplicate_of: PathBuf,
+    },
+    /// An atomic run failed and `src` is back where it was.
+    Restored {
+        src: PathBuf,
+    },
+    /// An atomic run failed and the copy or link at `dst` was deleted.
+    Withdrawn {
+        dst: PathBuf,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct Outcome {
+    /// Journal run id, for `undo`. `None` for dry runs and runs that
+    /// journaled nothing, such as when every file was skipped.
+    pub run_id: Option<String>,
+    /// How many moves crossed filesystems and were copied.
+    pub copied: usize,
+    /// Reflinks the filesystem couldn't make, done as plain copies.
+    pub fell_back: usize,
+    /// Moves that failed, with `keep_going`.
+    pub failures: Vec<Failure>,
+    /// Source directories removed because the run left them empty.
+    pub pruned: Vec<PathBuf>,
+    /// Set when an atomic run failed and was undone.
+    pub rolled_back: Option<RollBack>,
+}
+
+/// How an atomic run was undone.
+#[derive(Debug)]
+pub struct RollBack {
+    /// The failure that stopped the run.
+    pub cause: Failure,
+    /// How many files were put back.
+    pub restored: usize,
+    /// What couldn't be put back; empty when everything is as it was.
+    pub stuck: Vec<Failure>,
+}
+
+/// A change an atomic run made, kept so it can be reversed.
+enum Step {
+    /// `src` was moved to `dst`. The file it replaced is at `aside`.
+    Moved {
+        src: PathBuf,
+        dst: PathBuf,
+        aside: Option<PathBuf>,
+    },
+    /// A copy or link of `src` was made at `dst`, over the file at `aside`.
+    Placed {
+        src: PathBuf,
+        dst: PathBuf,
+        aside: Option<PathBuf>,
+    },
+    /// `src`, identical to `duplicate_of`, waits at `aside` to be deleted.
+    SetAside {
+        src: PathBuf,
+        aside: PathBuf,
+        duplicate_of: PathBuf,
+        size: u64,
+    },
+    CreatedDir(PathBuf),
+}
+
+impl Executor {
+    /// Carry out `plan` on `fs`, calling `on_event` after each move.
+    pub fn execute(
+        &self,
+        fs: &dyn FileSystem,
+        plan: &Plan,
+        mut on_event: impl FnMut(Event),
+    ) -> io::Result<Outcome> {
+        if plan.moves.is_empty() {
+            return Ok(Outcome::default());
+        }
+        if self.dry_run {
+            return self.preview(fs, plan, on_event);
+        }
+
+        // Every change is journaled so `undo` can reverse it. Opening the
+        // journal creates the root, and any folders above it that are missing.
+        let created = missing_dirs(fs, &plan.root);
+        let mut journal = Journal::open(fs, &plan.root)?;
+        let mut outcome = Outcome::default();
+        let mut steps = Vec::new();
+        for planned in &plan.moves {
+            match self.carry_out(fs, &mut journal, planned, &mut steps) {
+                Ok(event) => {
+                    if let Event::Moved {
+                        how: Moved::Copied, ..
+                    } = event
+                    {
+                        match self.mode {
+                            Mode::Move => outcome.copied += 1,
+                            Mode::Reflink => outcome.fell_back += 1,
+                            _ => {}
+                        }
+                    }
+                    on_event(event);
+                }
+                Err(e) if self.atomic => {
+                    let cause = Failure::from_error(&e, &planned.src, "move");
+                    let rollback = roll_back(fs, &mut journal, steps, cause, &mut on_event);
+                    if rollback.stuck.is_empty() && !created.is_empty() {
+                        // Leave no trace, not even the journal.
+                        let _ = fs.remove_file(&plan.root.join(JOURNAL_FILE));
+                        for dir in &created {
+                            let _ = fs.remove_dir(dir);
+                        }
+                    }
+                    return Ok(Outcome {
+                        rolled_back: Some(rollback),
+                        ..Outcome::default()
+                    });
+                }
+                Err(e) if self.keep_going && Failure::of(&e).is_some() => {
+                    outcome
+                        .failures
+                        .push(Failure::from_error(&e, &planned.src, "move"));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        commit(fs, &mut journal, steps)?;
+        if let Some(folder) = &self.prune_below
+            && self.mode == Mode::Move
+        {
+            for planned in &plan.moves {
+                if fs.symlink_metadata(&planned.src).is_err() {
+                    outcome
+                        .pruned
+                        .extend(journal::remove_empty_parents(fs, &planned.src, folder));
+                }
+            }
+        }
+        if !journal.is_empty() {
+            outcome.run_id = Some(journal.run_id().to_string());
+        }
+        Ok(outcome)
+    }
+
+    /// Make one move and journal it, adding what changed to `steps` for an
+    /// atomic run. Errors about the file carry a `Failure`; journal errors
+    /// don't.
+    fn carry_out(
+        &self,
+        fs: &dyn FileSystem,
+        journal: &mut Journal,
+        planned: &PlannedMove,
+        steps: &mut Vec<Step>,
+    ) -> io::Result<Event> {
+        let src = &planned.src;
+        let action = self
+            .resolve(fs, src, &planned.dst, |p| {
+                fs.symlink_metadata(p).is_ok().then(|| p.to_path_buf())
+            })
+            .map_err(failure::at(src, "resolve"))?;
+        Ok(match action {
+            Action::Move(dst) | Action::Replace(dst) => {
+                let replaced = fs.symlink_metadata(&dst).is_ok();
+                let aside = if self.atomic && replaced {
+                    let aside = aside_path(&dst, "replaced");
+                    fs.rename(&dst, &aside)
+                        .map_err(failure::at(&dst, "set aside"))?;
+                    Some(aside)
+                } else {
+                    None
+                };
+                let moved = self
+                    .create_parent(fs, &dst, steps)
+                    .and_then(|()| mover::place(fs, src, &dst, self.mode, self.verify));
+                let how = match moved {
+                    Ok(how) => how,
+                    Err(e) => {
+                        if let Some(aside) = &aside {
+                            let _ = fs.rename(aside, &dst);
+                        }
+                        return Err(e);
+                    }
+                };
+                if self.atomic {
+                    let (src, dst) = (src.clone(), dst.clone());
+                    steps.push(match self.mode {
+                        Mode::Move => Step::Moved { src, dst, aside },
+                        _ => Step::Placed { src, dst, aside },
+                    });
+                }
+                // Recorded as it landed: a moved symlink may have been
+                // recreated, so `undo` must expect the new link.
+                let meta = fs.symlink_metadata(&dst)?;
+                match self.mode {
+                    Mode::Move => {
+                        journal.record_move(src, &dst, meta.len, meta.modified, replaced)?
+                    }
+                    mode => {
+                        journal.record_copy(src, &dst, mode, meta.len, meta.modified, replaced)?
+                    }
+                }
+                Event::Moved {
+                    src: src.clone(),
+                    dst,
+                    replaced,
+                    how,
+                }
+            }
+            Action::Skip(reason) => Event::Skipped {
+                src: src.clone(),
+                reason,
+            },
+            Action::RemoveSource(dup) => {
+                let size = fs
+                    .symlink_metadata(src)
+                    .map_err(failure::at(src, "metadata"))?
+                    .len;
+                if self.atomic {
+                    let aside = aside_path(src, "duplicate");
+                    fs.rename(src, &aside)
+                        .map_err(failure::at(src, "set aside"))?;
+                    steps.push(Step::SetAside {
+                        src: src.clone(),
+                        aside,
+                        duplicate_of: dup.clone(),
+                        size,
+                    });
+                } else {
+                    fs.remove_file(src).map_err(failure::at(src, "remove"))?;
+                    journal.record_remove(src, &dup, size)?;
+                }
+                Event::Removed {
+                    src: src.clone(),
+                    duplicate_of: dup,
+                }
+            }
+        })
+    }
+
+    /// Decide what to do with `src`, going by the mode as well as the
+    /// conflict policy.
+    fn resolve(
+        &self,
+        fs: &dyn FileSystem,
+        src: &Path,
+        dst: &Path,
+        occupant: impl Fn(&Path) -> Option<PathBuf>,
+    ) -> io::Result<Action> {
+        if let Some(reason) = self.already_placed(fs, src, dst) {
+            return Ok(Action::Skip(reason));
+        }
+        Ok(
+            match conflict::resolve(fs, src, dst, self.on_conflict, occupant)? {
+                // Only a move may take the original away.
+                Action::RemoveSource(_) if self.mode != Mode::Move => {
+                    Action::Skip("an identical file is already there")
+                }
+                action => action,
+            },
+        )
+    }
+
+    /// Why nothing needs doing when `dst` already is the copy or link of
+    /// `src` this mode would make, as after organizing the same folder twice.
+    fn already_placed(&self, fs: &dyn FileSystem, src: &Path, dst: &Path) -> Option<&'static str> {
+        let there = fs.symlink_metadata(dst).ok()?;
+        let here = fs.symlink_metadata(src).ok()?;
+        let placed = match self.mode {
+            Mode::Move => false,
+            Mode::Hardlink => here.file_id.is_some() && here.file_id == there.file_id,
+            Mode::Symlink => fs.read_link(dst).ok()? == std::path::absolute(src).ok()?,
+            Mode::Copy | Mode::Reflink => {
+                here.len == there.len
+                    && here.modified == there.modified
+                    && mover::same_contents(fs, src, dst).ok()?
+            }
+        };
+        placed.then_some(match self.mode {
+            Mode::Hardlink | Mode::Symlink => "already linked",
+            _ => "already copied",
+        })
+    }
+
+    /// Create the directory `dst` goes in, noting for an atomic run which
+    /// directories didn't exist yet.
+    fn create_parent(
+        &self,
+        fs: &dyn FileSystem,
+        dst: &Path,
+        steps: &mut Vec<Step>,
+    ) -> io::Result<()> {
+        let Some(parent) = dst.parent() else {
+            return Ok(());
+        };
+        let missing = if self.atomic {
+            missing_dirs(fs, parent)
+        } else {
+            Vec::new()
+        };
+        fs.create_dir_all(parent)
+            .map_err(failure::at(parent, "create_dir"))?;
+        steps.extend(missing.into_iter().rev().map(Step::CreatedDir));
+        Ok(())
+    }
+
+    fn preview(
+        &self,
+        fs: &dyn FileSystem,
+        plan: &Plan,
+        mut on_event: impl FnMut(Event),
+    ) -> io::Result<Outcome> {
+        // Nothing moves, so track which source each destination would end up
+        // holding to resolve conflicts the way the real run would.
+        let mut planned: HashMap<PathBuf, PathBuf> = HashMap::new();
+        let mut outcome = Outcome::default();
+
+        for planned_move in &plan.moves {
+            let src = &planned_move.src;
+            let action = match self.resolve(fs, src, &planned_move.dst, |p| {
+                planned
+                    .get(p)
+                    .cloned()
+                    .or_else(|| fs.symlink_metadata(p).is_ok().then(|| p.to_path_buf()))
+            }) {
+                Ok(action) => action,
+                Err(e) if self.keep_going => {
+                    outcome
+                        .failures
+                        .push(Failure::from_error(&e, src, "resolve"));
+                    continue;
+                }
+                Err(e) => return Err(failure::at(src, "resolve")(e)),
+            };
+            let event = match action {
+                Action::Move(dst) | Action::Replace(dst) => {
+                    let replaced = planned.contains_key(&dst) || fs.symlink_metadata(&dst).is_ok();
+                    planned.insert(dst.clone(), src.clone());
+                    Event::Moved {
+                        src: src.clone(),
+                        dst,
+                        replaced,
+                        how: match self.mode {
+                            Mode::Move => Moved::Renamed,
+                            Mode::Copy => Moved::Copied,
+                            Mode::Hardlink | Mode::Symlink => Moved::Linked,
+                            Mode::Reflink => Moved::Cloned,
+                        },
+                    }
+                }
+                Action::Skip(reason) => Event::Skipped {
+                    src: src.clone(),
+                    reason,
+                },
+                Action::RemoveSource(dup) => Event::Removed {
+                    src: src.clone(),
+                    duplicate_of: dup,
+                },
+            };
+            on_event(event);
+        }
+        Ok(outcome)
+    }
+}
+
+/// Reverse an atomic run's `steps`, latest first.
+fn roll_back(
+    fs: &dyn FileSystem,
+    journal: &mut Journal,
+    steps: Vec<Step>,
+    cause: Failure,
+    on_event: &mut impl FnMut(Event),
+) -> RollBack {
+    let mut restored = 0;
+    let mut stuck = Vec::new();
+    for step in steps.into_iter().rev() {
+        let (path, result, event) = match step {
+            Step::Moved { src, dst, aside } => {
+                let result = mover::move_file(fs, &dst, &src, false)
+                    .and_then(|_| undone(fs, journal, &src, &dst, aside));
+                (src.clone(), result, Some(Event::Restored { src }))
+            }
+            Step::Placed { src, dst, aside } => {
+                let result = fs
+                    .remove_file(&dst)
+                    .and_then(|()| undone(fs, journal, &src, &dst, aside));
+                (dst.clone(), result, Some(Event::Withdrawn { dst }))
+            }
+            Step::SetAside { src, aside, .. } => {
+                let result = fs.rename(&aside, &src);
+                (src.clone(), result, Some(Event::Restored { src }))
+            }
+            Step::CreatedDir(dir) => {
+                let result = fs.remove_dir(&dir);
+                (dir, result, None)
+            }
+        };
+        match result {
+            Ok(()) => {
+                if let Some(event) = event {
+                    restored += 1;
+                    on_event(event);
+                }
+            }
+            Err(e) => stuck.push(Failure::from_error(&e, &path, "roll back")),
+        }
+    }
+    RollBack {
+        cause,
+        restored,
+        stuck,
+    }
+}
+
+/// `dir` and the folders above it that don't exist, innermost first.
+fn missing_dirs(fs: &dyn FileSystem, dir: &Path) -> Vec<PathBuf> {
+    let mut missing = Vec::new();
+    let mut dir = Some(dir);
+    while let Some(d) = dir
+        && !d.as_os_str().is_empty()
+        && fs.symlink_metadata(d).is_err()
+    {
+        missing.push(d.to_path_buf());
+        dir = d.parent();
+    }
+    missing
+}
+
+/// Journal a reversed move or copy and put back the file it replaced.
+fn undone(
+    fs: &dyn FileSystem,
+    journal: &mut Journal,
+    src: &Path,
+    dst: &Path,
+    aside: Option<PathBuf>,
+) -> io::Result<()> {
+    // The journal may be what failed; putting files back matters more.
+    let _ = journal.record_undo(src, dst);
+    match aside {
+        Some(aside) => fs.rename(&aside, dst),
+        None => Ok(()),
+    }
+}
+
+/// Finish an atomic run: delete what was set aside, journaling the removed
+/// duplicates now that they are gone.
+fn commit(fs: &dyn FileSystem, journal: &mut Journal, steps: Vec<Step>) -> io::Result<()> {
+    for step in steps {
+        match step {
+            Step::Moved {
+                aside: Some(aside), ..
+            }
+            | Step::Placed {
+                aside: Some(aside), ..
+            } => fs.remove_file(&aside)?,
+            Step::SetAside {
+                src,
+                aside,
+                duplicate_of,
+                size,
+            } => {
+                fs.remove_file(&aside)?;
+                journal.record_remove(&src, &duplicate_of, size)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// `dir/name` -> `dir/.name.<what>-<pid>`, beside the original.
+fn aside_path(path: &Path, what: &str) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.{}-{}", name, what, std::process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::{self, Entry};
+    use crate::plan::tests::planner;
+    use crate::vfs::{MemoryFs, Op};
+
+    fn executor(on_conflict: OnConflict) -> Executor {
+        Executor {
+            mode: Mode::Move,
+            on_conflict,
+            verify: false,
+            dry_run: false,
+            keep_going: false,
+            atomic: false,
+            prune_below: None,
+        }
+    }
+
+    fn atomic(on_conflict: OnConflict) -> Executor {
+        Executor {
+            atomic: true,
+            ..executor(on_conflict)
+        }
+    }
+
+    fn plan(fs: &MemoryFs) -> Plan {
+        planner("/in/organized").plan(fs, Path::new("/in")).unwrap()
+    }
+
+    /// Every path with the contents of the files, journal left out.
+    fn snapshot(fs: &MemoryFs) -> Vec<(PathBuf, Option<Vec<u8>>)> {
+        fs.paths()
+            .into_iter()
+            .filter(|p| !p.ends_with(JOURNAL_FILE))
+            .map(|p| {
+                let data = fs.read_file(&p).ok();
+                (p, data)
+            })
+            .collect()
+    }
+
+    fn path(p: &str) -> &Path {
+        Path::new(p)
+    }
+
+    #[test]
+    fn organize_renames_on_conflict() {
+        let fs = MemoryFs::new();
+        fs.write_file("/in/a.txt", "new").unwrap();
+        fs.write_file("/in/b.png", "png").unwrap();
+        fs.write_file("/in/organized/txt/a.txt", "old").unwrap();
+
+        let plan = planner("/in/organized")
+            .plan(&fs, Path::new("/in"))
+            .unwrap();
+        assert_eq!(plan.moves.len(), 2, "the destination isn't planned");
+        let outcome = executor(OnConflict::Rename)
+            .execute(&fs, &plan, |_| {})
+            .unwrap();
+
+        assert!(outcome.run_id.is_some());
+        assert_eq!(fs.read_file("/in/organized/txt/a.txt").unwrap(), b"old");
+        assert_eq!(fs.read_file("/in/organized/txt/a (1).txt").unwrap(), b"new");
+        assert_eq!(fs.read_file("/in/organized/png/b.png").unwrap(), b"png");
+        assert!(fs.symlink_metadata(Path::new("/in/a.txt")).is_err());
+    }
+
+    #[test]
+    fn organize_copies_across_a_mount() {
+        let fs = MemoryFs::new();
+        fs.write_file("/in/a.txt", "a").unwrap();
+        fs.create_dir_all(Path::new("/usb")).unwrap();
+        fs.mount("/usb");
+
+        let plan = planner("/usb/organized")
+            .plan(&fs, Path::new("/in"))
+            .unwrap();
+        let mut copied = Vec::new();
+        let outcome = executor(OnConflict::Rename)
+            .execute(&fs, &plan, |event| {
+                if let Event::Moved { how, .. } = event {
+                    copied.push(how);
+                }
+            })
+            .unwrap();
+
+        assert_eq!(copied, [Moved::Copied]);
+        assert_eq!(outcome.copied, 1);
+        assert_eq!(fs.read_file("/usb/organized/txt/a.txt").unwrap(), b"a");
+        assert!(fs.symlink_metadata(Path::new("/in/a.txt")).is_err());
+        // No temporary copy is left beside it.
+        assert_eq!(
+            fs.read_dir(Path::new("/usb/organized/txt")).unwrap(),
+            [Path::new("/usb/organized/txt/a.txt")]
+        );
+    }
+
+    #[test]
+    fn failure_on_a_later_move_restores_the_tree() {
+        let fs = MemoryFs::new();
+        fs.write_file("/in/a.txt", "a").unwrap();
+        fs.write_file("/in/b.png", "b").unwrap();
+        fs.write_file("/in/c.txt", "c").unwrap();
+        fs.fail(Op::Rename, "/in/c.txt", io::ErrorKind::PermissionDenied);
+        let before = snapshot(&fs);
+
+        let mut events = Vec::new();
+        let outcome = atomic(OnConflict::Rename)
+            .execute(&fs, &plan(&fs), |e| events.push(e))
+            .unwrap();
+
+        let rollback = outcome.rolled_back.expect("the run was rolled back");
+        assert_eq!(rollback.cause.path, path("/in/c.txt"));
+        assert_eq!(rollback.restored, 2);
+        assert!(rollback.stuck.is_empty());
+        assert!(outcome.run_id.is_none());
+        // Restored latest first.
+        let restored: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Restored { src } => Some(src.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(restored, [path("/in/b.png"), path("/in/a.txt")]);
+        // The created directories, the journal and the root are all gone.
+        assert_eq!(snapshot(&fs), before);
+        assert!(fs.symlink_metadata(path("/in/organized")).is_err());
+    }
+
+    #[test]
+    fn folders_made_above_a_new_root_are_removed() {
+        let fs = MemoryFs::new();
+        fs.write_file("/in/a.txt", "a").unwrap();
+        fs.write_file("/in/c.txt", "c").unwrap();
+        fs.fail(Op::Rename, "/in/c.txt", io::ErrorKind::PermissionDenied);
+        let before = snapshot(&fs);
+
+        let plan = planner("/new/deep/organized")
+            .plan(&fs, path("/in"))
+            .unwrap();
+        let outcome = atomic(OnConflict::Rename)
+            .execute(&fs, &plan, |_| {})
+            .unwrap();
+
+        assert!(outcome.rolled_back.unwrap().stuck.is_empty());
+        assert_eq!(snapshot(&fs), before);
+        assert!(fs.symlink_metadata(path("/new")).is_err());
+    }
+
+    #[test]
+    fn an_existing_root_keeps_its_journal_of_the_rollback() {
+        let fs = MemoryFs::new();
+        fs.write_file("/in/a.txt", "a").unwrap();
+        fs.write_file("/in/c.txt", "c").unwrap();
+        fs.create_dir_all(path("/in/organized")).unwrap();
+        fs.fail(Op::Rename, "/in/c.txt", io::ErrorKind::PermissionDenied);
+        let before = snapshot(&fs);
+
+        let outcome = atomic(OnConflict::Rename)
+            .execute(&fs, &plan(&fs), |_| {})
+            .unwrap();
+
+        assert!(outcome.rolled_back.is_some());
+        assert_eq!(snapshot(&fs), before);
+        let entries = journal::read(&fs, path("/in/organized")).unwrap();
+        assert!(matches!(
+            entries.as_slice(),
+            [Entry::Move { .. }, Entry::Undo { .. }]
+        ));
+    }
+
+    #[test]
+    fn overwritten_files_come_back_from_aside() {
+        let fs = MemoryFs::new();
+        fs.write_file("/in/a.txt", "new").unwrap();
+        fs.write_file("/in/b.png", "b").unwrap();
+        fs.write_file("/in/z.txt", "z").unwrap();
+        fs.write_file("/in/organized/txt/a.txt", "old").unwrap();
+        fs.fail(Op::Rename, "/in/z.txt", io::ErrorKind::PermissionDenied);
+        let before = snapshot(&fs);
+
+        let outcome = atomic(OnConflict::Overwrite)
+            .execute(&fs, &plan(&fs), |_| {})
+            .unwrap();
+
+        assert!(outcome.rolled_back.unwrap().stuck.is_empty());
+        assert_eq!(snapshot(&fs), before);
+        assert_eq!(fs.read_file("/in/organized/txt/a.txt").unwrap(), b"old");
+        assert!(fs.symlink_metadata(path("/in/organized/png")).is_err());
+    }
+
+    #[test]
+    fn a_successful_run_deletes_what_it_set_aside() {
+        let fs = MemoryFs::new();
+        fs.write_file("/in/a.txt", "new").unwrap();
+        fs.write_file("/in/organized/txt/a.txt", "old").unwrap();
+
+        let outcome = atomic(OnConflict::Overwrite)
+            .execute(&fs, &plan(&fs), |_| {})
+            .unwrap();
+
+        assert!(outcome.rolled_back.is_none());
+        assert!(outcome.run_id.is_some());
+        assert_eq!(fs.read_file("/in/organized/txt/a.txt").unwrap(), b"new");
+        assert_eq!(
+            fs.read_dir(path("/in/organized/txt")).unwrap(),
+            [path("/in/organized/txt/a.txt")]
+        );
+    }
+
+    #[test]
+    fn a_run_that_skips_everything_has_no_run_id() {
+        let fs = MemoryFs::new();
+        fs.write_file("/in/a.txt", "new").unwrap();
+        fs.write_file("/in/organized/txt/a.txt", "old").unwrap();
+
+        let outcome = atomic(OnConflict::Skip)
+            .execute(&fs, &plan(&fs), |_| {})
+            .unwrap();
+
+        assert!(outcome.run_id.is_none());
+        assert!(
+            journal::read(&fs, path("/in/organized"))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn duplicates_are_set_aside_until_the_run_succeeds() {
+        let fs = MemoryFs::new();
+        fs.write_file("/in/a.txt", "same").unwrap();
+        fs.write_file("/in/z.txt", "z").unwrap();
+        fs.write_file("/in/organized/txt/a.txt", "same").unwrap();
+        fs.fail(Op::Rename, "/in/z.txt", io::ErrorKind::PermissionDenied);
+        let before = snapshot(&fs);
+
+        let outcome = atomic(OnConflict::DedupeIfIdentical)
+            .execute(&fs, &plan(&fs), |_| {})
+            .unwrap();
+        let rollback = outcome.rolled_back.unwrap();
+        assert_eq!(rollback.restored, 1);
+        assert_eq!(snapshot(&fs), before);
+
+        fs.clear_faults();
+        let outcome = atomic(OnConflict::DedupeIfIdentical)
+            .execute(&fs, &plan(&fs), |_| {})
+            .unwrap();
+        assert!(outcome.rolled_back.is_none());
+        assert_eq!(
+            fs.read_dir(path("/in")).unwrap(),
+            [path("/in/organized")],
+            "the duplicate and its aside copy are gone"
+        );
+        let entries = journal::read(&fs, path("/in/organized")).unwrap();
+        assert!(
+            entries
+                .iter()
+                .any(|e| matches!(e, Entry::Remove { src, .. } if src == path("/in/a.txt")))
+        );
+    }
+
+    #[test]
+    fn aside_paths_are_hidden_beside_the_original() {
+        let aside = aside_path(path("/in/organized/txt/a.txt"), "replaced");
+        assert_eq!(aside.parent(), Some(path("/in/organized/txt")));
+        assert_eq!(
+            aside.file_name().unwrap().to_string_lossy(),
+            format!(".a.txt.replaced-{}", std::process::id())
+        );
+    }
+}
@@ -0,0 +1,78 @@
+// Errors tied to the file they happened on.
+//
+// Everything still returns `io::Result`, but an error about one file wraps a
+// `Failure` naming the file and what was being done to it. With
+// `--keep-going`, those are noted and the command carries on with the next
+// file; errors without one (a folder that can't be listed at all, a journal
+// that can't be written) still stop it.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug)]
+pub struct Failure {
+    pub path: PathBuf,
+    /// What was being done, e.g. `rename` or `read_dir`.
+    pub op: &'static str,
+    pub kind: io::ErrorKind,
+    pub message: String,
+}
+
+impl Failure {
+    /// The failure `error` carries, or one blaming `path` and `op` if it
+    /// doesn't carry any.
+    pub fn from_error(error: &io::Error, path: &Path, op: &'static str) -> Failure {
+        match Failure::of(error) {
+            Some(failure) => failure.clone(),
+            None => Failure {
+                path: path.to_path_buf(),
+                op,
+                kind: error.kind(),
+                message: error.to_string(),
+            },
+        }
+    }
+
+    /// The failure inside `error`, if it is about one file.
+    pub fn of(error: &io::Error) -> Option<&Failure> {
+        error.get_ref()?.downcast_ref::<Failure>()
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:?}: {}", self.op, self.path, self.message)
+    }
+}
+
+impl Error for Failure {}
+
+/// For `map_err`: tag an error with the file it is about and the operation,
+/// keeping its kind. Errors already tagged keep their original tag.
+pub fn at<'a>(path: &'a Path, op: &'static str) -> impl FnOnce(io::Error) -> io::Error + 'a {
+    move |error| {
+        if Failure::of(&error).is_some() {
+            return error;
+        }
+        let kind = error.kind();
+        io::Error::new(kind, Failure::from_error(&error, path, op))
+    }
+}
+
+/// Print a table of what failed, one row per file.
+pub fn write_summary(out: &mut impl Write, failures: &[Failure]) -> io::Result<()> {
+    writeln!(out, "\n{} file(s) failed:", failures.len())?;
+    writeln!(out, "  {:<20}  {:<12}  PATH", "ERROR", "OPERATION")?;
+    for failure in failures {
+        writeln!(
+            out,
+            "  {:<20}  {:<12}  {}",
+            format!("{:?}", failure.kind),
+            failure.op,
+            failure.path.display()
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,346 @@
+// Choosing which entries a walk looks at.
+//
+// `--include`, `--exclude`, `--exclude-from` files and `.gitignore`/`.ignore`
+// files all use .gitignore syntax:
+//
+//     *.lock          a name without a slash matches at any depth
+//     /build          a leading or inner slash anchors it to the folder
+//     node_modules/   a trailing slash matches only directories
+//     !keep.lock      a leading `!` brings back what an earlier line excluded
+//
+// The last matching line wins. Ignore files apply to their own directory and
+// everything under it, and the command-line patterns override them. An
+// excluded directory is not descended into at all.
+//
+// Hidden entries (dotfiles and dot-directories) have their own policy, since
+// tools expect files like `.bashrc` to stay where they are.
+
+use crate::vfs::FileSystem;
+use clap::ValueEnum;
+use glob::MatchOptions;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+/// Ignore files honored when `Filter::ignore_files` is set, in the order
+/// they apply.
+pub const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+/// What to do with hidden entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Hidden {
+    /// Leave out dotfiles and dot-directories
+    Skip,
+    /// Treat them like anything else
+    #[default]
+    Include,
+    /// Only look at dotfiles and files inside dot-directories
+    Only,
+}
+
+/// Whether `rel`, relative to the walked folder, is a dotfile or inside a
+/// dot-directory.
+pub fn is_hidden(rel: &Path) -> bool {
+    rel.components().any(|c| match c {
+        Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+        _ => false,
+    })
+}
+
+/// Which entries a walk skips.
+#[derive(Default)]
+pub struct Filter {
+    /// If any are given, only files matching one of them are kept.
+    /// Directories are always walked.
+    include: Vec<Pattern>,
+    exclude: Patterns,
+    /// Also honor `.gitignore` and `.ignore` files in the walked folders.
+    pub ignore_files: bool,
+    pub hidden: Hidden,
+}
+
+impl Filter {
+    pub fn new(include: &[String], exclude: &[String]) -> io::Result<Filter> {
+        let include = include
+            .iter()
+            .map(|line| match Pattern::parse(line)? {
+                Some(pattern) if !pattern.negated => Ok(pattern),
+                _ => Err(invalid(format!("bad include pattern {:?}", line))),
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Filter {
+            include,
+            exclude: Patterns::parse(Path::new(""), exclude)?,
+            ignore_files: false,
+            hidden: Hidden::default(),
+        })
+    }
+
+    /// Add the patterns in `path`, one per line, after the ones already given.
+    pub fn exclude_from(&mut self, fs: &dyn FileSystem, path: &Path) -> io::Result<()> {
+        let mut text = String::new();
+        fs.open(path)?.read_to_string(&mut text)?;
+        let more = Patterns::parse(Path::new(""), text.lines())
+            .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+        self.exclude.patterns.extend(more.patterns);
+        Ok(())
+    }
+
+    /// Whether the entry at `rel`, relative to the walked folder, is left
+    /// out. `ignores` are the ignore files above it, outermost first.
+    pub fn excludes<'p>(
+        &self,
+        rel: &Path,
+        is_dir: bool,
+        ignores: impl IntoIterator<Item = &'p Patterns>,
+    ) -> bool {
+        match self.hidden {
+            Hidden::Skip if is_hidden(rel) => return true,
+            Hidden::Only if !is_dir && !is_hidden(rel) => return true,
+            _ => {}
+        }
+
+        let mut excluded = false;
+        for patterns in ignores {
+            if let Some(verdict) = patterns.matched(rel, is_dir) {
+                excluded = verdict;
+            }
+        }
+        if let Some(verdict) = self.exclude.matched(rel, is_dir) {
+            excluded = verdict;
+        }
+        if excluded {
+            return true;
+        }
+        !is_dir && !self.include.is_empty() && !self.include.iter().any(|p| p.matches(rel, false))
+    }
+
+    /// The patterns from the ignore files directly in `dir`, whose path
+    /// relative to the walked folder is `rel_dir`. `None` if there are none
+    /// or ignore files aren't honored.
+    pub fn ignores_in(
+        &self,
+        fs: &dyn FileSystem,
+        dir: &Path,
+        rel_dir: &Path,
+    ) -> io::Result<Option<Patterns>> {
+        if !self.ignore_files {
+            return Ok(None);
+        }
+        let mut lines = String::new();
+        for name in IGNORE_FILES {
+            let path = dir.join(name);
+            match fs.open(&path) {
+                Ok(mut file) => {
+                    file.read_to_string(&mut lines)?;
+                    lines.push('\n');
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if lines.is_empty() {
+            return Ok(None);
+        }
+        Patterns::parse(rel_dir, lines.lines()).map(Some)
+    }
+}
+
+/// Patterns from one source, matched against paths relative to `base`.
+#[derive(Default)]
+pub struct Patterns {
+    base: PathBuf,
+    patterns: Vec<Pattern>,
+}
+
+impl Patterns {
+    /// Parse .gitignore-style lines; blank lines and `#` comments are skipped.
+    pub fn parse<S: AsRef<str>>(
+        base: &Path,
+        lines: impl IntoIterator<Item = S>,
+    ) -> io::Result<Patterns> {
+        let mut patterns = Vec::new();
+        for line in lines {
+            patterns.extend(Pattern::parse(line.as_ref())?);
+        }
+        Ok(Patterns {
+            base: base.to_path_buf(),
+            patterns,
+        })
+    }
+
+    /// `Some(true)` if the last pattern matching `rel` excludes it,
+    /// `Some(false)` if it brings it back, `None` if none match.
+    fn matched(&self, rel: &Path, is_dir: bool) -> Option<bool> {
+        let rel = rel.strip_prefix(&self.base).ok()?;
+        self.patterns
+            .iter()
+            .rev()
+            .find(|p| p.matches(rel, is_dir))
+            .map(|p| !p.negated)
+    }
+}
+
+struct Pattern {
+    glob: glob::Pattern,
+    negated: bool,
+    dir_only: bool,
+    /// Matched against the whole relative path rather than the name.
+    anchored: bool,
+}
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+impl Pattern {
+    fn parse(line: &str) -> io::Result<Option<Pattern>> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+        let glob = glob::Pattern::new(line)
+            .map_err(|e| invalid(format!("bad pattern {:?}: {}", line, e)))?;
+        Ok(Some(Pattern {
+            glob,
+            negated,
+            dir_only,
+            anchored,
+        }))
+    }
+
+    fn matches(&self, rel: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            self.glob.matches_path_with(rel, MATCH_OPTIONS)
+        } else {
+            rel.file_name().is_some_and(|name| {
+                self.glob
+                    .matches_with(&name.to_string_lossy(), MATCH_OPTIONS)
+            })
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFs;
+
+    fn excluded(filter: &Filter, rel: &str, is_dir: bool) -> bool {
+        filter.excludes(Path::new(rel), is_dir, [])
+    }
+
+    fn exclude(lines: &[&str]) -> Filter {
+        let lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+        Filter::new(&[], &lines).unwrap()
+    }
+
+    #[test]
+    fn a_later_negation_brings_a_file_back() {
+        let filter = exclude(&["*.log", "!keep.log", "# a comment", ""]);
+        assert!(excluded(&filter, "a.log", false));
+        assert!(excluded(&filter, "deep/b.log", false));
+        assert!(!excluded(&filter, "deep/keep.log", false));
+
+        let filter = exclude(&["!keep.log", "*.log"]);
+        assert!(excluded(&filter, "keep.log", false), "the last match wins");
+    }
+
+    #[test]
+    fn a_slash_anchors_to_the_folder() {
+        let filter = exclude(&["/build", "docs/*.md"]);
+        assert!(excluded(&filter, "build", true));
+        assert!(!excluded(&filter, "src/build", true));
+        assert!(excluded(&filter, "docs/a.md", false));
+        assert!(!excluded(&filter, "x/docs/a.md", false));
+        assert!(
+            !excluded(&filter, "docs/sub/a.md", false),
+            "* stops at a slash"
+        );
+    }
+
+    #[test]
+    fn a_trailing_slash_matches_only_directories() {
+        let filter = exclude(&["cache/"]);
+        assert!(excluded(&filter, "cache", true));
+        assert!(excluded(&filter, "deep/cache", true));
+        assert!(!excluded(&filter, "cache", false));
+    }
+
+    #[test]
+    fn includes_keep_only_matching_files() {
+        let filter = Filter::new(&["*.jpg".to_string()], &[]).unwrap();
+        assert!(!excluded(&filter, "a.jpg", false));
+        assert!(excluded(&filter, "a.png", false));
+        assert!(!excluded(&filter, "photos", true));
+        assert!(Filter::new(&["!*.jpg".to_string()], &[]).is_err());
+    }
+
+    #[test]
+    fn ignore_files_apply_below_their_directory() {
+        let fs = MemoryFs::new();
+        fs.write_file("/w/.gitignore", "*.tmp\n").unwrap();
+        fs.write_file("/w/sub/.ignore", "!keep.tmp\n").unwrap();
+        let filter = Filter {
+            ignore_files: true,
+            ..Filter::default()
+        };
+        let root = filter
+            .ignores_in(&fs, Path::new("/w"), Path::new(""))
+            .unwrap()
+            .unwrap();
+        let sub = filter
+            .ignores_in(&fs, Path::new("/w/sub"), Path::new("sub"))
+            .unwrap()
+            .unwrap();
+        let excluded = |rel: &str, ignores: &[&Patterns]| {
+            filter.excludes(Path::new(rel), false, ignores.iter().copied())
+        };
+
+        assert!(excluded("a.tmp", &[&root]));
+        assert!(excluded("keep.tmp", &[&root]));
+        assert!(!excluded("sub/keep.tmp", &[&root, &sub]));
+        assert!(excluded("sub/other.tmp", &[&root, &sub]));
+        // The command line overrides ignore files.
+        let mut filter = exclude(&["sub/keep.tmp"]);
+        filter.ignore_files = true;
+        assert!(filter.excludes(Path::new("sub/keep.tmp"), false, [&root, &sub]));
+
+        let plain = Filter::default();
+        assert!(
+            plain
+                .ignores_in(&fs, Path::new("/w"), Path::new(""))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn exclude_from_reads_patterns_from_a_file() {
+        let fs = MemoryFs::new();
+        fs.write_file("/excludes", "*.bak\n/private/\n").unwrap();
+        let mut filter = Filter::default();
+        filter.exclude_from(&fs, Path::new("/excludes")).unwrap();
+        assert!(excluded(&filter, "a.bak", false));
+        assert!(excluded(&filter, "private", true));
+        assert!(!excluded(&filter, "a.txt", false));
+    }
+}
@@ -0,0 +1,591 @@
+// Append-only record of what organize runs did, and the `undo` that reads it.
+//
+// The journal is NDJSON at `<organized root>/.file_organizer-journal.jsonl`.
+// Entries are written right after each filesystem change succeeds, so even
+// a run that dies halfway leaves an accurate record. Undo never deletes
+// journal lines; it appends `undo` entries for what it restored, which lets
+// a partially undone run be retried later.
+//
+// Runs that copy or link instead of moving leave the originals alone, so
+// undoing them just deletes what they made.
+
+use crate::mover::{self, Mode};
+use crate::template::civil_date;
+use crate::vfs::FileSystem;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const JOURNAL_FILE: &str = ".file_organizer-journal.jsonl";
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Entry {
+    /// `src` was moved to `dst`. `replaced` is set when it went over an
+    /// existing file, whose old contents are gone for good.
+    Move {
+        run: String,
+        src: PathBuf,
+        dst: PathBuf,
+        size: u64,
+        mtime_ns: u64,
+        #[serde(default)]
+        replaced: bool,
+    },
+    /// A copy or link of `src` was made at `dst`; `src` stayed where it was.
+    Copy {
+        run: String,
+        src: PathBuf,
+        dst: PathBuf,
+        mode: Mode,
+        size: u64,
+        mtime_ns: u64,
+        #[serde(default)]
+        replaced: bool,
+    },
+    /// `src` was deleted because it was identical to `duplicate_of`.
+    Remove {
+        run: String,
+        src: PathBuf,
+        duplicate_of: PathBuf,
+        size: u64,
+    },
+    /// A move or copy from `run` was reversed.
+    Undo {
+        run: String,
+        src: PathBuf,
+        dst: PathBuf,
+    },
+}
+
+impl Entry {
+    fn is_undoable(&self) -> bool {
+        matches!(self, Entry::Move { .. } | Entry::Copy { .. })
+    }
+
+    fn run(&self) -> &str {
+        match self {
+            Entry::Move { run, .. }
+            | Entry::Copy { run, .. }
+            | Entry::Remove { run, .. }
+            | Entry::Undo { run, .. } => run,
+        }
+    }
+}
+
+pub struct Journal<'a> {
+    fs: &'a dyn FileSystem,
+    path: PathBuf,
+    run: String,
+    /// Entries appended for this run so far.
+    entries: usize,
+}
+
+impl<'a> Journal<'a> {
+    /// Open (creating if needed) the journal under `root` for a new run.
+    pub fn open(fs: &'a dyn FileSystem, root: &Path) -> io::Result<Journal<'a>> {
+        Journal::resume(fs, root, new_run_id())
+    }
+
+    /// Open the journal under `root` to append entries for an existing run.
+    fn resume(fs: &'a dyn FileSystem, root: &Path, run: String) -> io::Result<Journal<'a>> {
+        fs.create_dir_all(root)?;
+        Ok(Journal {
+            fs,
+            path: root.join(JOURNAL_FILE),
+            run,
+            entries: 0,
+        })
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run
+    }
+
+    /// Whether nothing has been recorded for this run, so there is nothing
+    /// for `undo` to reverse.
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Record a completed move. `size` and `modified` describe the file as it
+    /// was moved, so undo can tell whether it has been touched since.
+    pub fn record_move(
+        &mut self,
+        src: &Path,
+        dst: &Path,
+        size: u64,
+        modified: Option<SystemTime>,
+        replaced: bool,
+    ) -> io::Result<()> {
+        // Absolute paths, so undo works from any working directory.
+        self.append(&Entry::Move {
+            run: self.run.clone(),
+            src: resolved(self.fs, src)?,
+            dst: resolved(self.fs, dst)?,
+            size,
+            mtime_ns: modified.map(nanos_since_epoch).unwrap_or(0),
+            replaced,
+        })
+    }
+
+    /// Record a copy or link made by `mode`, described as for `record_move`.
+    pub fn record_copy(
+        &mut self,
+        src: &Path,
+        dst: &Path,
+        mode: Mode,
+        size: u64,
+        modified: Option<SystemTime>,
+        replaced: bool,
+    ) -> io::Result<()> {
+        self.append(&Entry::Copy {
+            run: self.run.clone(),
+            src: resolved(self.fs, src)?,
+            dst: resolved(self.fs, dst)?,
+            mode,
+            size,
+            mtime_ns: modified.map(nanos_since_epoch).unwrap_or(0),
+            replaced,
+        })
+    }
+
+    pub fn record_remove(&mut self, src: &Path, duplicate_of: &Path, size: u64) -> io::Result<()> {
+        self.append(&Entry::Remove {
+            run: self.run.clone(),
+            src: resolved(self.fs, src)?,
+            duplicate_of: resolved(self.fs, duplicate_of)?,
+            size,
+        })
+    }
+
+    /// Record that a move or copy made earlier in this run was reversed.
+    pub fn record_undo(&mut self, src: &Path, dst: &Path) -> io::Result<()> {
+        self.append(&Entry::Undo {
+            run: self.run.clone(),
+            src: resolved(self.fs, src)?,
+            dst: resolved(self.fs, dst)?,
+        })
+    }
+
+    fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.fs.append(&self.path, line.as_bytes())?;
+        self.entries += 1;
+        Ok(())
+    }
+}
+
+/// Runs started so far by this process.
+static RUNS_STARTED: AtomicU32 = AtomicU32::new(0);
+
+/// Run ids sort chronologically: `20261015-143205-4711` (UTC date, time, pid).
+/// A process making several runs, as `watch` does, numbers the later ones
+/// (`20261015-143205-4711.2`) so runs within one second stay apart.
+fn new_run_id() -> String {
+    let n = RUNS_STARTED.fetch_add(1, Ordering::Relaxed) + 1;
+    let now = SystemTime::now();
+    let (year, month, day) = civil_date(now);
+    let secs_of_day = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % 86_400;
+    let id = format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}-{}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        std::process::id()
+    );
+    match n {
+        1 => id,
+        n => format!("{}.{}", id, n),
+    }
+}
+
+/// Absolute path with symlinks and `..` resolved in the parent directory,
+/// which must exist. The file name itself is kept as-is.
+fn resolved(fs: &dyn FileSystem, path: &Path) -> io::Result<PathBuf> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            Ok(fs.canonicalize(parent)?.join(name))
+        }
+        _ => std::path::absolute(path),
+    }
+}
+
+pub(crate) fn nanos_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+pub fn read(fs: &dyn FileSystem, root: &Path) -> io::Result<Vec<Entry>> {
+    let file = match fs.open(&root.join(JOURNAL_FILE)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("journal line {}: {}", n + 1, e),
+            )
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Moves and copies of each run that haven't been undone yet, oldest run
+/// first.
+fn pending_moves(entries: &[Entry]) -> Vec<(String, Vec<&Entry>)> {
+    let undone: HashSet<(&str, &Path, &Path)> = entries
+        .iter()
+        .filter_map(|e| match e {
+            Entry::Undo { run, src, dst } => Some((run.as_str(), src.as_path(), dst.as_path())),
+            _ => None,
+        })
+        .collect();
+
+    let mut runs: Vec<(String, Vec<&Entry>)> = Vec::new();
+    for entry in entries {
+        let pending = match entry {
+            Entry::Move { run, src, dst, .. } | Entry::Copy { run, src, dst, .. } => {
+                !undone.contains(&(run.as_str(), src.as_path(), dst.as_path()))
+            }
+            Entry::Remove { .. } => true,
+            Entry::Undo { .. } => false,
+        };
+        if !pending {
+            continue;
+        }
+        match runs.iter_mut().find(|(id, _)| id == entry.run()) {
+            Some((_, list)) => list.push(entry),
+            None => runs.push((entry.run().to_string(), vec![entry])),
+        }
+    }
+    // A run whose moves are all undone has nothing left but removals, which
+    // can't be reversed; don't offer it as the default again.
+    runs.retain(|(_, list)| list.iter().any(|e| e.is_undoable()));
+    runs
+}
+
+/// The runs with moves or copies left to undo, oldest first, with how many
+/// files each has left.
+pub fn list_runs(fs: &dyn FileSystem, root: &Path) -> io::Result<Vec<(String, usize)>> {
+    let entries = read(fs, root)?;
+    Ok(pending_moves(&entries)
+        .into_iter()
+        .map(|(run, list)| (run, list.iter().filter(|e| e.is_undoable()).count()))
+        .collect())
+}
+
+/// What `undo` did with one journal entry, or in a dry run would do.
+#[derive(Debug)]
+pub enum Event {
+    /// `dst` was moved back to `src`. `replaced` is set when the move had
+    /// gone over a file that can't be recovered.
+    Restored {
+        src: PathBuf,
+        dst: PathBuf,
+        replaced: bool,
+    },
+    /// The copy or link at `dst` was deleted; `src` was never touched.
+    Removed {
+        src: PathBuf,
+        dst: PathBuf,
+        mode: Mode,
+        replaced: bool,
+    },
+    /// `dst` was left alone because it changed since the run, or its
+    /// original place is taken again.
+    Skipped { dst: PathBuf, reason: String },
+    /// Putting `dst` back failed.
+    Failed { dst: PathBuf, error: io::Error },
+    /// `src` was deleted as a duplicate of `duplicate_of` and is gone.
+    Unrecoverable { src: PathBuf, duplicate_of: PathBuf },
+}
+
+#[derive(Debug, Default)]
+pub struct Undone {
+    /// The run undone; `None` if there was nothing to undo.
+    pub run: Option<String>,
+    /// Entries that could not be restored.
+    pub failed: usize,
+}
+
+/// Reverse the moves of `run` (default: the latest run with anything left to
+/// undo), reporting each entry to `on_event`.
+pub fn undo(
+    fs: &dyn FileSystem,
+    root: &Path,
+    run: Option<&str>,
+    dry_run: bool,
+    mut on_event: impl FnMut(Event),
+) -> io::Result<Undone> {
+    let entries = read(fs, root)?;
+    let runs = pending_moves(&entries);
+    let found = match run {
+        Some(id) => runs.iter().find(|(r, _)| r == id),
+        None => runs.last(),
+    };
+    let Some((run, list)) = found else {
+        return Ok(Undone::default());
+    };
+    // Journal paths are canonical; match that when pruning empty folders.
+    let root = &fs.canonicalize(root)?;
+
+    let mut journal = if dry_run {
+        None
+    } else {
+        Some(Journal::resume(fs, root, run.clone())?)
+    };
+    let mut failed = 0;
+
+    for entry in list.iter().rev() {
+        match entry {
+            Entry::Move {
+                src,
+                dst,
+                size,
+                mtime_ns,
+                replaced,
+                ..
+            } => {
+                if let Err(reason) = check_restorable(fs, src, dst, *size, *mtime_ns) {
+                    on_event(Event::Skipped {
+                        dst: dst.clone(),
+                        reason,
+                    });
+                    failed += 1;
+                    continue;
+                }
+                if !dry_run {
+                    // A failure here is about this file only; carry on with
+                    // the rest of the run.
+                    let restored = src
+                        .parent()
+                        .map_or(Ok(()), |parent| fs.create_dir_all(parent))
+                        .and_then(|()| mover::move_file(fs, dst, src, false));
+                    if let Err(error) = restored {
+                        on_event(Event::Failed {
+                            dst: dst.clone(),
+                            error,
+                        });
+                        failed += 1;
+                        continue;
+                    }
+                    if let Some(journal) = journal.as_mut() {
+                        journal.append(&Entry::Undo {
+                            run: run.clone(),
+                            src: src.clone(),
+                            dst: dst.clone(),
+                        })?;
+                    }
+                    remove_empty_parents(fs, dst, root);
+                }
+                on_event(Event::Restored {
+                    src: src.clone(),
+                    dst: dst.clone(),
+                    replaced: *replaced,
+                });
+            }
+            Entry::Copy {
+                src,
+                dst,
+                mode,
+                size,
+                mtime_ns,
+                replaced,
+                ..
+            } => {
+                if let Err(reason) = check_unchanged(fs, dst, *size, *mtime_ns) {
+                    on_event(Event::Skipped {
+                        dst: dst.clone(),
+                        reason,
+                    });
+                    failed += 1;
+                    continue;
+                }
+                if !dry_run {
+                    if let Err(error) = fs.remove_file(dst) {
+                        on_event(Event::Failed {
+                            dst: dst.clone(),
+                            error,
+                        });
+                        failed += 1;
+                        continue;
+                    }
+                    if let Some(journal) = journal.as_mut() {
+                        journal.append(&Entry::Undo {
+                            run: run.clone(),
+                            src: src.clone(),
+                            dst: dst.clone(),
+                        })?;
+                    }
+                    remove_empty_parents(fs, dst, root);
+                }
+                on_event(Event::Removed {
+                    src: src.clone(),
+                    dst: dst.clone(),
+                    mode: *mode,
+                    replaced: *replaced,
+                });
+            }
+            Entry::Remove {
+                src, duplicate_of, ..
+            } => {
+                on_event(Event::Unrecoverable {
+                    src: src.clone(),
+                    duplicate_of: duplicate_of.clone(),
+                });
+                failed += 1;
+            }
+            Entry::Undo { .. } => {}
+        }
+    }
+
+    Ok(Undone {
+        run: Some(run.clone()),
+        failed,
+    })
+}
+
+/// The file must still be at `dst`, unchanged, and `src` must be free.
+fn check_restorable(
+    fs: &dyn FileSystem,
+    src: &Path,
+    dst: &Path,
+    size: u64,
+    mtime_ns: u64,
+) -> Result<(), String> {
+    check_unchanged(fs, dst, size, mtime_ns)?;
+    if fs.symlink_metadata(src).is_ok() {
+        return Err(format!("{:?} exists again; refusing to overwrite it", src));
+    }
+    Ok(())
+}
+
+/// The file at `dst` must still be the one the run left there.
+fn check_unchanged(
+    fs: &dyn FileSystem,
+    dst: &Path,
+    size: u64,
+    mtime_ns: u64,
+) -> Result<(), String> {
+    let meta = match fs.symlink_metadata(dst) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err("deleted or moved since the run".to_string());
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+    let modified = meta.modified.map(nanos_since_epoch).unwrap_or(0);
+    if meta.len != size || modified != mtime_ns {
+        return Err("changed since the run".to_string());
+    }
+    Ok(())
+}
+
+/// Remove directories left empty under `root` after moving `path` away.
+/// Returns the ones removed.
+pub(crate) fn remove_empty_parents(fs: &dyn FileSystem, path: &Path, root: &Path) -> Vec<PathBuf> {
+    let mut removed = Vec::new();
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if d == root || !d.starts_with(root) || fs.remove_dir(d).is_err() {
+            break;
+        }
+        removed.push(d.to_path_buf());
+        dir = d.parent();
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::{MemoryFs, Op};
+
+    #[test]
+    fn runs_in_one_process_get_their_own_ids() {
+        let ids: HashSet<String> = (0..5).map(|_| new_run_id()).collect();
+        assert_eq!(ids.len(), 5);
+    }
+
+    #[test]
+    fn undo_reverses_only_the_latest_of_two_quick_runs() {
+        let fs = MemoryFs::new();
+        let root = Path::new("/in/organized");
+        fs.write_file("/in/organized/txt/a.txt", "a").unwrap();
+        fs.write_file("/in/organized/txt/b.txt", "b").unwrap();
+        for name in ["a.txt", "b.txt"] {
+            let dst = root.join("txt").join(name);
+            let meta = fs.metadata(&dst).unwrap();
+            let mut journal = Journal::open(&fs, root).unwrap();
+            journal
+                .record_move(
+                    &Path::new("/in").join(name),
+                    &dst,
+                    meta.len,
+                    meta.modified,
+                    false,
+                )
+                .unwrap();
+        }
+
+        let entries = read(&fs, root).unwrap();
+        assert_eq!(pending_moves(&entries).len(), 2);
+        assert_eq!(undo(&fs, root, None, false, |_| {}).unwrap().failed, 0);
+        assert!(fs.metadata(Path::new("/in/b.txt")).is_ok());
+        assert!(fs.metadata(Path::new("/in/organized/txt/a.txt")).is_ok());
+    }
+
+    #[test]
+    fn a_failed_restore_does_not_stop_the_rest() {
+        let fs = MemoryFs::new();
+        let root = Path::new("/in/organized");
+        let mut journal = Journal::open(&fs, root).unwrap();
+        for name in ["a.txt", "b.txt"] {
+            let dst = root.join("txt").join(name);
+            fs.write_file(&dst, name).unwrap();
+            let meta = fs.metadata(&dst).unwrap();
+            journal
+                .record_move(
+                    &Path::new("/in").join(name),
+                    &dst,
+                    meta.len,
+                    meta.modified,
+                    false,
+                )
+                .unwrap();
+        }
+        // Whichever order undo takes, b.txt comes back although a.txt fails.
+        fs.fail(
+            Op::Rename,
+            "/in/organized/txt/a.txt",
+            io::ErrorKind::PermissionDenied,
+        );
+
+        assert_eq!(undo(&fs, root, None, false, |_| {}).unwrap().failed, 1);
+        assert!(fs.metadata(Path::new("/in/b.txt")).is_ok());
+        assert!(fs.metadata(Path::new("/in/organized/txt/a.txt")).is_ok());
+    }
+}
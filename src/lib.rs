@@ -0,0 +1,46 @@
+//! Scan folders and organize the files in them.
+//!
+//! The pieces the `file_organizer` binary is built from, for tools that want
+//! them without shelling out:
+//!
+//! - [`Scanner`] walks a folder and yields its entries; [`scan`] summarises
+//!   them into a [`Report`].
+//! - [`Planner`] asks a [`Classifier`] where each file belongs and returns a
+//!   [`Plan`] of moves, without touching anything. [`classify`] has
+//!   classifiers for extension, size and regex, and [`Composite`] to nest
+//!   them; categories, dates and rules files are classifiers too.
+//! - [`Executor`] carries a plan out (or previews it), resolving conflicts and
+//!   journaling every move so [`journal::undo`] can reverse the run.
+//! - [`prune::run`] removes the empty folders a run leaves behind.
+//! - [`watch::Watcher`] (Linux) hands back files as they finish arriving in a
+//!   folder, for [`Planner::plan_files`].
+//!
+//! All of them work through a [`FileSystem`]: [`RealFs`] for the disk, or
+//! [`MemoryFs`] to run scenarios in memory, with injected faults.
+
+pub mod category;
+pub mod classify;
+pub mod conflict;
+pub mod dates;
+pub mod dupes;
+pub mod execute;
+pub mod failure;
+pub mod filter;
+pub mod journal;
+pub mod mover;
+pub mod output;
+pub mod plan;
+pub mod prune;
+pub mod rules;
+pub mod scan;
+pub mod sniff;
+pub mod template;
+pub mod vfs;
+#[cfg(target_os = "linux")]
+pub mod watch;
+
+pub use classify::{Classifier, Composite, FileInfo};
+pub use execute::{Event, Executor, Outcome, RollBack};
+pub use plan::{Plan, PlannedMove, Planner};
+pub use scan::{Entry, Report, ScanOptions, Scanner, WalkOptions, scan};
+pub use vfs::{FileSystem, MemoryFs, RealFs};
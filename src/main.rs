@@ -1,11 +1,35 @@
-use clap::{Parser, Subcommand};
-use std::collections::BTreeMap;
-use std::fs;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use file_organizer::category::{self, Categories};
+use file_organizer::classify::{ByExtension, SizeBuckets};
+use file_organizer::conflict::OnConflict;
+use file_organizer::dates::{self, DateGrouping, DateSource};
+use file_organizer::dupes::{self, DupeAction, DupeOptions, HashAlgo, Keep};
+use file_organizer::failure::{self, Failure};
+use file_organizer::filter::{Filter, Hidden};
+use file_organizer::journal;
+use file_organizer::mover::{Mode, Moved};
+use file_organizer::output::{self, OutputFormat};
+use file_organizer::plan::Layout;
+use file_organizer::prune::{self, PruneOptions};
+use file_organizer::rules::RuleSet;
+use file_organizer::scan::Symlinks;
+use file_organizer::sniff::ClassifyBy;
+#[cfg(target_os = "linux")]
+use file_organizer::watch::Watcher;
+use file_organizer::{
+    Classifier, Composite, Event, Executor, Plan, Planner, RealFs, ScanOptions, WalkOptions,
+};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "file_organizer")]
 #[command(about = "Scan and organize files in a folder")]
+#[command(
+    after_help = "Exit status: 0 if everything went through, 1 if the command failed, 2 if some files failed (see --keep-going)."
+)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
@@ -17,9 +41,40 @@ enum Commands {
     Scan {
         /// Folder to scan
         folder: PathBuf,
+
+        /// Descend into subdirectories
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Maximum depth to descend (implies --recursive; 1 = top level only)
+        #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        max_depth: Option<usize>,
+
+        /// Only count entries at this depth or deeper
+        #[arg(long, default_value_t = 1)]
+        min_depth: usize,
+
+        /// Number of largest files to list
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+
+        /// Classify files by extension, detected content, or both
+        #[arg(long, value_enum, default_value_t = ClassifyBy::Extension)]
+        classify_by: ClassifyBy,
+
+        #[command(flatten)]
+        filters: Filters,
+
+        /// Note entries that can't be read and carry on, listing them at the end
+        #[arg(long)]
+        keep_going: bool,
     },
 
-    /// Organize files into subfolders by extension
+    /// Organize files into subfolders by extension, category, date or rules
     Organize {
         /// Folder to organize
         folder: PathBuf,
@@ -27,145 +82,884 @@ enum Commands {
         /// Show what would happen without moving files
         #[arg(long)]
         dry_run: bool,
+
+        /// Save the planned moves to FILE for `apply` instead of moving anything
+        #[arg(long, value_name = "FILE")]
+        plan_out: Option<PathBuf>,
+
+        /// Also organize files in subfolders, removing the ones left empty
+        #[arg(long)]
+        recursive: bool,
+
+        /// Where files from subfolders go in the organized tree
+        #[arg(long, value_enum, default_value_t = Layout::Flatten, requires = "recursive")]
+        layout: Layout,
+
+        /// Move files, or copy or link them and leave the originals where they are
+        #[arg(long, value_enum, default_value_t = Mode::Move)]
+        mode: Mode,
+
+        #[command(flatten)]
+        grouping: Grouping,
+
+        #[command(flatten)]
+        filters: Filters,
+
+        #[command(flatten)]
+        moves: MoveOptions,
     },
+
+    /// Keep organizing files as they arrive in a folder (Linux only)
+    Watch {
+        /// Folder to watch
+        folder: PathBuf,
+
+        /// Seconds a file must sit unchanged before it is organized
+        #[arg(long, value_name = "SECS", default_value_t = 2)]
+        settle: u64,
+
+        /// Show what would happen to each file without moving it
+        #[arg(long)]
+        dry_run: bool,
+
+        #[command(flatten)]
+        grouping: Grouping,
+
+        #[command(flatten)]
+        filters: Filters,
+
+        #[command(flatten)]
+        moves: MoveOptions,
+    },
+
+    /// Carry out a plan saved with `organize --plan-out`
+    Apply {
+        /// Plan file to apply
+        plan: PathBuf,
+
+        #[command(flatten)]
+        moves: MoveOptions,
+
+        /// Check the plan and show what would happen without moving files
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Find files with identical contents
+    Dupes {
+        /// Folder to search (recursively)
+        folder: PathBuf,
+
+        /// Hash used to confirm duplicates
+        #[arg(long, value_enum, default_value_t = HashAlgo::Blake3)]
+        hash: HashAlgo,
+
+        /// What to do with the extra copies
+        #[arg(long, value_enum, default_value_t = DupeAction::Report)]
+        action: DupeAction,
+
+        /// Directory extra copies go to with `--action move-to`
+        #[arg(long, value_name = "DIR", required_if_eq("action", "move-to"))]
+        to: Option<PathBuf>,
+
+        /// Which copy of each set to keep
+        #[arg(long, value_enum, default_value_t = Keep::Oldest)]
+        keep: Keep,
+
+        /// Always keep copies inside this directory if there are any
+        #[arg(long, value_name = "DIR")]
+        prefer: Option<PathBuf>,
+
+        /// Ignore files smaller than this many bytes
+        #[arg(long, default_value_t = 1)]
+        min_size: u64,
+
+        /// Show what would happen without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Reverse an organize run using its journal
+    Undo {
+        /// Folder that was organized
+        folder: PathBuf,
+
+        /// Destination used for that run, if it wasn't FOLDER/organized
+        #[arg(long, value_name = "PATH")]
+        dest: Option<PathBuf>,
+
+        /// Run id to undo (defaults to the most recent run)
+        #[arg(long)]
+        run: Option<String>,
+
+        /// List runs that can be undone
+        #[arg(long, conflicts_with = "run")]
+        list: bool,
+
+        /// Show what would be restored without moving files
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Remove empty folders, including ones holding only .DS_Store, Thumbs.db or desktop.ini
+    PruneEmpty {
+        /// Folder to clean up; it is never removed itself
+        folder: PathBuf,
+
+        /// Never remove this directory or anything in it (repeatable)
+        #[arg(long, value_name = "DIR")]
+        protect: Vec<PathBuf>,
+
+        /// Show what would be removed without removing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+/// Where `organize` and `watch` put files.
+#[derive(Args)]
+struct Grouping {
+    /// Where organized files go (default: FOLDER/organized); may be outside FOLDER
+    #[arg(long, value_name = "PATH")]
+    dest: Option<PathBuf>,
+
+    /// How to group files into subfolders; several nest, e.g. `category,date`
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [GroupBy::Extension],
+        conflicts_with = "rules"
+    )]
+    by: Vec<GroupBy>,
+
+    /// Add or override a category mapping, e.g. `Images=heic,avif` (repeatable)
+    #[arg(long = "category", value_name = "NAME=EXTS", value_parser = category::parse_assignment)]
+    categories: Vec<(String, Vec<String>)>,
+
+    /// Folder pattern for `--by date`; placeholders: {year}, {month}, {day}, {ext}
+    #[arg(long, default_value = dates::DEFAULT_PATTERN)]
+    pattern: String,
+
+    /// Where dates come from for `--by date`, tried in order
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = dates::DEFAULT_SOURCES.to_vec()
+    )]
+    date_source: Vec<DateSource>,
+
+    /// TOML rules file deciding where each file goes
+    #[arg(long, value_name = "FILE")]
+    rules: Option<PathBuf>,
+
+    /// Classify files by extension, detected content, or both
+    #[arg(long, value_enum, default_value_t = ClassifyBy::Extension)]
+    classify_by: ClassifyBy,
 }
 
+/// Which files `scan`, `organize` and `watch` look at. Patterns use .gitignore syntax.
+#[derive(Args)]
+struct Filters {
+    /// Only look at files matching this pattern, e.g. `*.jpg` (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Leave out files and folders matching this pattern, e.g. `*.lock` (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Read more exclude patterns from FILE, one per line
+    #[arg(long, value_name = "FILE")]
+    exclude_from: Vec<PathBuf>,
+
+    /// Also leave out what .gitignore and .ignore files exclude
+    #[arg(long)]
+    gitignore: bool,
+
+    /// Dotfiles and dot-directories [default: skip for organize and watch, include for scan]
+    #[arg(long, value_enum)]
+    hidden: Option<Hidden>,
+
+    /// What to do with symlinks
+    #[arg(long, value_enum, default_value_t = Symlinks::Skip)]
+    symlinks: Symlinks,
+}
+
+/// How planned moves are carried out.
+#[derive(Args)]
+struct MoveOptions {
+    /// What to do when the destination file already exists
+    #[arg(long, value_enum, default_value_t = OnConflict::Rename)]
+    on_conflict: OnConflict,
+
+    /// Re-read and compare files copied across filesystems before deleting the source
+    #[arg(long)]
+    verify: bool,
+
+    /// Note files that fail and carry on with the rest, listing them at the end
+    #[arg(long)]
+    keep_going: bool,
+
+    /// On any failure, move everything back and remove the folders the run created
+    #[arg(long, conflicts_with = "keep_going")]
+    atomic: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum GroupBy {
+    /// One folder per lowercased extension
+    Extension,
+    /// Built-in categories such as Images, Documents, Audio
+    Category,
+    /// Date folders following `--pattern`
+    Date,
+    /// Size folders: Tiny (<1K), Small (<1M), Medium (<100M), Large
+    Size,
+}
+
+/// Exit status when the command couldn't do its job at all.
+const EXIT_FATAL: i32 = 1;
+/// Exit status when some files failed but the rest were handled.
+const EXIT_PARTIAL: i32 = 2;
+
 fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Scan { folder } => match scan_folder(&folder) {
-            Ok(report) => print_report(&folder, &report),
-            Err(e) => {
-                eprintln!("Error scanning {:?}: {}", folder, e);
-                std::process::exit(1);
+        Commands::Scan {
+            folder,
+            recursive,
+            max_depth,
+            min_depth,
+            top,
+            format,
+            classify_by,
+            filters,
+            keep_going,
+        } => {
+            let opts = ScanOptions {
+                walk: WalkOptions {
+                    min_depth,
+                    max_depth: max_depth.unwrap_or(if recursive { usize::MAX } else { 1 }),
+                    follow_links: filters.symlinks == Symlinks::Follow,
+                },
+                filter: filter_for(filters, Hidden::Include),
+                top,
+                classify_by,
+                keep_going,
+            };
+            let report = match file_organizer::scan(&RealFs, &folder, &opts) {
+                Ok(report) => report,
+                Err(e) => {
+                    eprintln!("Error scanning {:?}: {}", folder, e);
+                    std::process::exit(EXIT_FATAL);
+                }
+            };
+
+            let mut out = std::io::stdout().lock();
+            let written = match format {
+                OutputFormat::Text => output::write_text(&mut out, &folder, &report),
+                OutputFormat::Json => output::write_json(&mut out, &folder, &report),
+                OutputFormat::Ndjson => output::write_ndjson(&mut out, &folder, &report),
+                OutputFormat::Csv => output::write_csv(&mut out, &folder, &report),
+            };
+            if let Err(e) = written {
+                eprintln!("Error writing report: {}", e);
+                std::process::exit(EXIT_FATAL);
             }
-        },
+            if report_failures(&report.failures) > 0 {
+                std::process::exit(EXIT_PARTIAL);
+            }
+        }
 
-        Commands::Organize { folder, dry_run } => {
-            if let Err(e) = organize_by_extension(&folder, dry_run) {
-                eprintln!("Error organizing {:?}: {}", folder, e);
-                std::process::exit(1);
+        Commands::Organize {
+            folder,
+            dry_run,
+            plan_out,
+            recursive,
+            layout,
+            mode,
+            grouping,
+            filters,
+            moves,
+        } => {
+            let planner = Planner {
+                recursive,
+                layout,
+                ..planner_for(&folder, grouping, filters, moves.keep_going)
+            };
+            let executor = Executor {
+                mode,
+                prune_below: recursive.then(|| folder.clone()),
+                ..executor_for(moves, dry_run)
+            };
+            match organize(&folder, &planner, &executor, plan_out.as_deref()) {
+                Ok(0) => {}
+                Ok(_) => std::process::exit(EXIT_PARTIAL),
+                Err(e) => {
+                    eprintln!("Error organizing {:?}: {}", folder, e);
+                    std::process::exit(EXIT_FATAL);
+                }
             }
         }
-    }
-}
 
-struct Report {
-    total_entries: usize,
-    files: usize,
-    dirs: usize,
-    by_extension: BTreeMap<String, usize>,
-}
+        Commands::Watch {
+            folder,
+            settle,
+            dry_run,
+            grouping,
+            filters,
+            moves,
+        } => {
+            let planner = planner_for(&folder, grouping, filters, moves.keep_going);
+            let executor = executor_for(moves, dry_run);
+            if let Err(e) = watch(&folder, &planner, &executor, Duration::from_secs(settle)) {
+                eprintln!("Error watching {:?}: {}", folder, e);
+                std::process::exit(EXIT_FATAL);
+            }
+        }
 
-fn scan_folder(folder: &PathBuf) -> std::io::Result<Report> {
-    let mut report = Report {
-        total_entries: 0,
-        files: 0,
-        dirs: 0,
-        by_extension: BTreeMap::new(),
-    };
+        Commands::Apply {
+            plan,
+            moves,
+            dry_run,
+        } => {
+            let executor = executor_for(moves, dry_run);
+            match apply(&plan, executor) {
+                Ok(0) => {}
+                Ok(_) => std::process::exit(EXIT_PARTIAL),
+                Err(e) => {
+                    eprintln!("Error applying {:?}: {}", plan, e);
+                    std::process::exit(EXIT_FATAL);
+                }
+            }
+        }
 
-    for entry in fs::read_dir(folder)? {
-        let entry = entry?;
-        report.total_entries += 1;
+        Commands::Dupes {
+            folder,
+            hash,
+            action,
+            to,
+            keep,
+            prefer,
+            min_size,
+            dry_run,
+        } => {
+            let opts = DupeOptions {
+                hash,
+                action,
+                move_to: to,
+                keep,
+                prefer,
+                min_size,
+                dry_run,
+            };
+            match find_dupes(&folder, &opts) {
+                Ok(0) => {}
+                Ok(failed) => {
+                    eprintln!("{} file(s) could not be processed", failed);
+                    std::process::exit(EXIT_PARTIAL);
+                }
+                Err(e) => {
+                    eprintln!("Error finding duplicates in {:?}: {}", folder, e);
+                    std::process::exit(EXIT_FATAL);
+                }
+            }
+        }
 
-        let path = entry.path();
-        let meta = entry.metadata()?;
+        Commands::Undo {
+            folder,
+            dest,
+            run,
+            list,
+            dry_run,
+        } => {
+            let organized_root = dest.unwrap_or_else(|| folder.join("organized"));
+            let result = if list {
+                list_runs(&organized_root).map(|()| 0)
+            } else {
+                undo(&organized_root, run.as_deref(), dry_run)
+            };
+            match result {
+                Ok(0) => {}
+                Ok(failed) => {
+                    eprintln!("{} file(s) could not be restored", failed);
+                    std::process::exit(EXIT_PARTIAL);
+                }
+                Err(e) => {
+                    eprintln!("Error undoing in {:?}: {}", folder, e);
+                    std::process::exit(EXIT_FATAL);
+                }
+            }
+        }
 
-        if meta.is_dir() {
-            report.dirs += 1;
-            continue;
+        Commands::PruneEmpty {
+            folder,
+            protect,
+            dry_run,
+        } => {
+            let opts = PruneOptions {
+                protected: protect,
+                dry_run,
+            };
+            match prune_empty(&folder, &opts) {
+                Ok(0) => {}
+                Ok(_) => std::process::exit(EXIT_PARTIAL),
+                Err(e) => {
+                    eprintln!("Error pruning {:?}: {}", folder, e);
+                    std::process::exit(EXIT_FATAL);
+                }
+            }
         }
-        if meta.is_file() {
-            report.files += 1;
+    }
+}
 
-            let ext = path
-                .extension()
-                .and_then(|s| s.to_str())
-                .map(|s| s.to_lowercase())
-                .unwrap_or_else(|| "(no_ext)".to_string());
+/// The planner for `--rules` or `--by`, exiting if either can't be loaded.
+fn planner_for(folder: &Path, grouping: Grouping, filters: Filters, keep_going: bool) -> Planner {
+    let classifier: Box<dyn Classifier> = match grouping.rules {
+        Some(path) => match RuleSet::load(&RealFs, &path) {
+            Ok(rules) => Box::new(rules),
+            Err(e) => {
+                eprintln!("Error loading rules: {}", e);
+                std::process::exit(EXIT_FATAL);
+            }
+        },
+        None => match classifier_for(
+            &grouping.by,
+            &grouping.categories,
+            &grouping.pattern,
+            grouping.date_source,
+        ) {
+            Ok(classifier) => classifier,
+            Err(e) => {
+                eprintln!("Error in --pattern: {}", e);
+                std::process::exit(EXIT_FATAL);
+            }
+        },
+    };
+    Planner {
+        classifier,
+        dest: grouping.dest.unwrap_or_else(|| folder.join("organized")),
+        classify_by: grouping.classify_by,
+        symlinks: filters.symlinks,
+        filter: filter_for(filters, Hidden::Skip),
+        recursive: false,
+        layout: Layout::Flatten,
+        keep_going,
+    }
+}
 
-            *report.by_extension.entry(ext).or_insert(0) += 1;
+/// The filter for `--include`/`--exclude` and `--hidden`, exiting if a
+/// pattern is bad.
+fn filter_for(filters: Filters, hidden: Hidden) -> Filter {
+    let built = Filter::new(&filters.include, &filters.exclude).and_then(|mut filter| {
+        for path in &filters.exclude_from {
+            filter.exclude_from(&RealFs, path)?;
+        }
+        filter.ignore_files = filters.gitignore;
+        filter.hidden = filters.hidden.unwrap_or(hidden);
+        Ok(filter)
+    });
+    match built {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("Error in filters: {}", e);
+            std::process::exit(EXIT_FATAL);
         }
     }
-
-    Ok(report)
 }
 
-fn print_report(folder: &PathBuf, report: &Report) {
-    println!("Folder: {:?}", folder);
-    println!("Total entries: {}", report.total_entries);
-    println!("Files: {}", report.files);
-    println!("Dirs: {}", report.dirs);
-    println!("\nFiles by extension:");
+fn executor_for(moves: MoveOptions, dry_run: bool) -> Executor {
+    Executor {
+        mode: Mode::Move,
+        on_conflict: moves.on_conflict,
+        verify: moves.verify,
+        dry_run,
+        keep_going: moves.keep_going,
+        atomic: moves.atomic,
+        prune_below: None,
+    }
+}
 
-    if report.by_extension.is_empty() {
-        println!("  (none)");
-        return;
+/// The classifier for `--by`, nesting them when more than one is given.
+fn classifier_for(
+    by: &[GroupBy],
+    categories: &[(String, Vec<String>)],
+    pattern: &str,
+    date_source: Vec<DateSource>,
+) -> std::io::Result<Box<dyn Classifier>> {
+    let mut parts: Vec<Box<dyn Classifier>> = Vec::new();
+    for group in by {
+        parts.push(match group {
+            GroupBy::Extension => Box::new(ByExtension),
+            GroupBy::Category => {
+                let mut table = Categories::builtin();
+                for (name, exts) in categories {
+                    table.assign(name, exts);
+                }
+                Box::new(table)
+            }
+            GroupBy::Date => Box::new(DateGrouping::new(pattern, date_source.clone())?),
+            GroupBy::Size => Box::new(SizeBuckets::default()),
+        });
     }
+    Ok(match parts.len() {
+        1 => parts.remove(0),
+        _ => Box::new(Composite::new(parts)),
+    })
+}
 
-    for (ext, count) in &report.by_extension {
-        println!("  {:>8}  {}", count, ext);
+/// Returns how many files failed, with `--keep-going`.
+fn organize(
+    folder: &Path,
+    planner: &Planner,
+    executor: &Executor,
+    plan_out: Option<&Path>,
+) -> std::io::Result<usize> {
+    // Safety: only organize files in the top-level of `folder` unless asked
+    // to recurse, and never anything already in the destination.
+    // Create subfolders like: organized/txt, organized/png, organized/no_ext,
+    // organized/Images, organized/2024/03, or wherever the rules file says.
+    let mut plan = planner.plan(&RealFs, folder)?;
+    plan.mode = executor.mode;
+    let mut failures = plan.failures.clone();
+    if plan.moves.is_empty() {
+        println!("No files to organize in {:?}", folder);
+    } else if let Some(path) = plan_out {
+        let mut out = BufWriter::new(File::create(path)?);
+        plan.write_json(&mut out)?;
+        out.flush()?;
+        println!(
+            "Saved {} planned move(s) to {:?}; nothing was moved. Run `apply` to carry them out.",
+            plan.moves.len(),
+            path
+        );
+    } else {
+        failures.extend(execute(&plan, executor)?);
     }
+    Ok(report_failures(&failures))
 }
 
-fn organize_by_extension(folder: &Path, dry_run: bool) -> std::io::Result<()> {
-    // Safety: only organize files in the top-level of `folder` (no recursion).
-    // Create subfolders like: organized/txt, organized/png, organized/no_ext
-    let organized_root = folder.join("organized");
+/// Organize files in `folder` as they settle, until interrupted.
+#[cfg(target_os = "linux")]
+fn watch(
+    folder: &Path,
+    planner: &Planner,
+    executor: &Executor,
+    settle: Duration,
+) -> std::io::Result<()> {
+    let mut watcher = Watcher::new(folder, settle)?;
+    println!(
+        "Watching {:?}; files go to {:?} once they settle. Press Ctrl-C to stop.",
+        folder, planner.dest
+    );
+    loop {
+        let ready = watcher.wait()?;
+        // One bad batch shouldn't stop the watch; report it and carry on.
+        let result = planner.plan_files(&RealFs, &ready).and_then(|plan| {
+            let mut failures = plan.failures.clone();
+            if !plan.moves.is_empty() {
+                failures.extend(execute(&plan, executor)?);
+            }
+            Ok(failures)
+        });
+        match result {
+            Ok(failures) => {
+                report_failures(&failures);
+            }
+            Err(e) => eprintln!("Error organizing {:?}: {}", ready, e),
+        }
+    }
+}
 
-    let mut moves: Vec<(PathBuf, PathBuf)> = Vec::new();
+#[cfg(not(target_os = "linux"))]
+fn watch(
+    _folder: &Path,
+    _planner: &Planner,
+    _executor: &Executor,
+    _settle: Duration,
+) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "watching needs inotify, which is only available on Linux",
+    ))
+}
 
-    for entry in fs::read_dir(folder)? {
-        let entry = entry?;
-        let path = entry.path();
+/// Apply a saved plan if none of its sources changed since it was made.
+/// Returns how many files failed, with `--keep-going`.
+fn apply(path: &Path, mut executor: Executor) -> std::io::Result<usize> {
+    let plan = Plan::read_json(BufReader::new(File::open(path)?))?;
+    let stale = plan.stale_sources(&RealFs);
+    if !stale.is_empty() {
+        println!("Out of date:");
+        for (src, reason) in &stale {
+            println!("  {:?}: {}", src, reason);
+        }
+        return Err(std::io::Error::other(format!(
+            "{} planned move(s) are out of date; nothing was moved",
+            stale.len()
+        )));
+    }
+    if plan.moves.is_empty() {
+        println!("Nothing to apply in {:?}", path);
+        return Ok(0);
+    }
+    // The plan says what is done with its files.
+    executor.mode = plan.mode;
+    let failures = execute(&plan, &executor)?;
+    Ok(report_failures(&failures))
+}
 
-        // Skip the "organized" folder itself and any directories.
-        if path.file_name().and_then(|n| n.to_str()) == Some("organized") {
-            continue;
+/// Find duplicates and act on them, printing each set. Returns how many
+/// files the action failed on.
+fn find_dupes(folder: &Path, opts: &DupeOptions) -> std::io::Result<usize> {
+    let mut sets = 0;
+    let outcome = dupes::run(&RealFs, folder, opts, |event| match event {
+        dupes::Event::Set { keep, extras, size } => {
+            sets += 1;
+            println!(
+                "Set {}: {} copies of {}, {} wasted",
+                sets,
+                extras + 1,
+                output::human_size(size),
+                output::human_size(size * extras as u64)
+            );
+            println!("  {:<7} {}", "keep", keep.display());
         }
-        let meta = entry.metadata()?;
-        if !meta.is_file() {
-            continue;
+        dupes::Event::Handled { path } => {
+            println!("  {:<7} {}", opts.action.label(), path.display())
         }
+        dupes::Event::Failed { path, error } => {
+            println!("  {:<7} {}: {}", "error", path.display(), error)
+        }
+        dupes::Event::Unreadable { path, error } => {
+            eprintln!("Skipping {:?}: {}", path, error)
+        }
+    })?;
 
-        let ext_folder = path
-            .extension()
-            .and_then(|s| s.to_str())
-            .map(|s| s.to_lowercase())
-            .unwrap_or_else(|| "no_ext".to_string());
+    if outcome.sets == 0 {
+        println!("No duplicates found in {:?}", folder);
+    } else {
+        println!(
+            "\n{} duplicate set(s), {} extra file(s), {} wasted",
+            outcome.sets,
+            outcome.extras,
+            output::human_size(outcome.wasted)
+        );
+        if opts.dry_run && opts.action != DupeAction::Report {
+            println!("Nothing was changed (dry-run).");
+        }
+    }
+    Ok(outcome.failed)
+}
 
-        let dest_dir = organized_root.join(ext_folder);
-        let file_name = path.file_name().unwrap(); // safe: it's a file path
-        let dest_path = dest_dir.join(file_name);
+/// Remove empty folders, printing each. Returns how many couldn't be looked
+/// at or removed.
+fn prune_empty(folder: &Path, opts: &PruneOptions) -> std::io::Result<usize> {
+    let verb = if opts.dry_run {
+        "Would remove"
+    } else {
+        "Removed"
+    };
+    let outcome = prune::run(&RealFs, folder, opts, |removed| match removed.junk {
+        0 => println!("{} {:?}", verb, removed.dir),
+        n => println!("{} {:?} (with {} junk file(s))", verb, removed.dir, n),
+    })?;
 
-        moves.push((path, dest_path));
+    if outcome.protected {
+        println!("{:?} is protected; nothing to do", folder);
+    } else if outcome.removed == 0 {
+        println!("No empty folders in {:?}", folder);
+    } else {
+        println!(
+            "\n{} {} empty folder(s) and {} junk file(s)",
+            verb, outcome.removed, outcome.junk
+        );
+        if opts.dry_run {
+            println!("Nothing was removed (dry-run).");
+        }
     }
+    Ok(report_failures(&outcome.failures))
+}
 
-    if moves.is_empty() {
-        println!("No files to organize in {:?}", folder);
+fn list_runs(root: &Path) -> std::io::Result<()> {
+    let runs = journal::list_runs(&RealFs, root)?;
+    if runs.is_empty() {
+        println!("No runs to undo in {:?}", root);
         return Ok(());
     }
+    println!("Runs that can be undone (oldest first):");
+    for (run, files) in &runs {
+        println!("  {}  {} file(s)", run, files);
+    }
+    Ok(())
+}
 
-    if dry_run {
-        println!("Dry run: planned moves");
-        for (src, dst) in &moves {
-            println!("  {:?} -> {:?}", src, dst);
+/// Undo `run` (default: the latest), printing each file. Returns how many
+/// could not be restored.
+fn undo(root: &Path, run: Option<&str>, dry_run: bool) -> std::io::Result<usize> {
+    let runs = journal::list_runs(&RealFs, root)?;
+    let found = match run {
+        Some(id) => runs.iter().find(|(r, _)| r == id),
+        None => runs.last(),
+    };
+    let Some((run, _)) = found else {
+        match run {
+            Some(id) => println!("Nothing to undo for run {:?} in {:?}", id, root),
+            None => println!("Nothing to undo in {:?}", root),
+        }
+        return Ok(0);
+    };
+
+    println!(
+        "{} run {}",
+        if dry_run { "Would undo" } else { "Undoing" },
+        run
+    );
+    let undone = journal::undo(&RealFs, root, Some(run), dry_run, |event| {
+        let replaced = match event {
+            journal::Event::Restored { src, dst, replaced } => {
+                if dry_run {
+                    println!("  {:?} -> {:?}", dst, src);
+                } else {
+                    println!("  Restored {:?} -> {:?}", dst, src);
+                }
+                replaced.then_some(dst)
+            }
+            journal::Event::Removed {
+                src,
+                dst,
+                mode,
+                replaced,
+            } => {
+                if dry_run {
+                    println!("  {:?} would be removed", dst);
+                } else {
+                    println!(
+                        "  Removed {:?} ({} from {:?}, which is untouched)",
+                        dst,
+                        mode.verb().to_lowercase(),
+                        src
+                    );
+                }
+                replaced.then_some(dst)
+            }
+            journal::Event::Skipped { dst, reason } => {
+                println!("  Skipped {:?}: {}", dst, reason);
+                None
+            }
+            journal::Event::Failed { dst, error } => {
+                println!("  Failed to undo {:?}: {}", dst, error);
+                None
+            }
+            journal::Event::Unrecoverable { src, duplicate_of } => {
+                println!(
+                    "  Cannot restore {:?}: it was deleted as a duplicate of {:?}",
+                    src, duplicate_of
+                );
+                None
+            }
+        };
+        if let Some(dst) = replaced {
+            println!(
+                "    note: the file this replaced at {:?} cannot be recovered",
+                dst
+            );
         }
+    })?;
+    Ok(undone.failed)
+}
+
+/// Carry out `plan`, printing each move. Returns the moves that failed.
+fn execute(plan: &Plan, executor: &Executor) -> std::io::Result<Vec<Failure>> {
+    if executor.dry_run {
+        println!("Dry run: planned moves");
+        let outcome = executor.execute(&RealFs, plan, |event| match event {
+            Event::Moved {
+                src, dst, replaced, ..
+            } => {
+                let note = if replaced { " (overwrite)" } else { "" };
+                println!("  {:?} -> {:?}{}", src, dst, note);
+            }
+            Event::Skipped { src, reason } => println!("  {:?} skipped: {}", src, reason),
+            Event::Removed { src, duplicate_of } => {
+                println!("  {:?} removed: identical to {:?}", src, duplicate_of)
+            }
+            Event::Restored { src } => println!("  {:?} restored", src),
+            Event::Withdrawn { dst } => println!("  {:?} deleted", dst),
+        })?;
         println!("\nNothing was moved (dry-run).");
-        return Ok(());
+        return Ok(outcome.failures);
     }
 
-    // Real move
-    for (src, dst) in moves {
-        if let Some(parent) = dst.parent() {
-            fs::create_dir_all(parent)?;
+    let outcome = executor.execute(&RealFs, plan, |event| match event {
+        Event::Moved { src, dst, how, .. } => {
+            let (verb, note) = match (executor.mode, how) {
+                (Mode::Move, Moved::Copied) => ("Moved", " (copied across filesystems)"),
+                (Mode::Reflink, Moved::Copied) => ("Copied", " (no reflink support there)"),
+                (mode, _) => (mode.verb(), ""),
+            };
+            println!("{} {:?} -> {:?}{}", verb, src, dst, note);
+        }
+        Event::Skipped { src, reason } => println!("Skipped {:?}: {}", src, reason),
+        Event::Removed { src, duplicate_of } => {
+            println!("Removed {:?}: identical to {:?}", src, duplicate_of)
         }
-        fs::rename(&src, &dst)?;
-        println!("Moved {:?} -> {:?}", src, dst);
+        Event::Restored { src } => println!("Restored {:?}", src),
+        Event::Withdrawn { dst } => println!("Deleted {:?}", dst),
+    })?;
+
+    if let Some(rollback) = outcome.rolled_back {
+        println!("\nFailed: {}", rollback.cause);
+        if rollback.stuck.is_empty() {
+            println!(
+                "Rolled back {} change(s); everything is back to its original state.",
+                rollback.restored
+            );
+        } else {
+            println!(
+                "Rolled back {} change(s), but some could not be undone:",
+                rollback.restored
+            );
+            let _ = failure::write_summary(&mut std::io::stdout().lock(), &rollback.stuck);
+        }
+        return Err(std::io::Error::new(
+            rollback.cause.kind,
+            "the run was rolled back (--atomic)",
+        ));
+    }
+    println!("\nDone. Files organized into {:?}", plan.root);
+    if outcome.copied > 0 {
+        println!(
+            "{} file(s) were on another filesystem and were copied, then deleted",
+            outcome.copied
+        );
+    }
+    if !outcome.pruned.is_empty() {
+        println!(
+            "Removed {} folder(s) the moves left empty",
+            outcome.pruned.len()
+        );
+    }
+    if outcome.fell_back > 0 {
+        println!(
+            "{} file(s) couldn't be reflinked there and were copied in full",
+            outcome.fell_back
+        );
     }
+    if let Some(run_id) = outcome.run_id {
+        println!("Run id: {} (use `undo` to reverse it)", run_id);
+    }
+    Ok(outcome.failures)
+}
 
-    println!("\nDone. Files organized into {:?}", organized_root);
-    Ok(())
-}
\ No newline at end of file
+/// Print the failure table to stderr if anything failed. Returns how many did.
+fn report_failures(failures: &[Failure]) -> usize {
+    if !failures.is_empty() {
+        // Nothing more can be done if stderr is gone.
+        let _ = failure::write_summary(&mut std::io::stderr().lock(), failures);
+    }
+    failures.len()
+}
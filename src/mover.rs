@@ -0,0 +1,247 @@
+// Moving files, including across filesystems.
+//
+// `fs::rename` fails with EXDEV when source and destination are on different
+// mounts. In that case we copy into a temporary file next to the destination,
+// carry over permissions and timestamps, fsync, optionally verify the bytes,
+// rename the temporary into place and only then delete the source. At no
+// point is there a half-written file under the destination name.
+//
+// A symlink is moved as a link, never by copying what it points to. A
+// relative target is relative to the link's directory, so a link moving to
+// another directory is recreated pointing at the same file by absolute path.
+//
+// The other modes leave the source where it is and put a copy or link at the
+// destination, built under a temporary name and renamed into place. A hard
+// link can't cross filesystems and that is an error; a reflink that the
+// filesystem can't make falls back to an ordinary copy.
+
+use crate::failure;
+use crate::vfs::FileSystem;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// What organizing does with each file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Move files into the organized tree
+    #[default]
+    Move,
+    /// Copy them, leaving the originals where they are
+    Copy,
+    /// Hard-link them; fails for files on another filesystem
+    Hardlink,
+    /// Symlink to the originals by absolute path
+    Symlink,
+    /// Clone them sharing storage (btrfs, xfs), copying where that isn't supported
+    Reflink,
+}
+
+impl Mode {
+    /// Past tense for reporting what was done to a file.
+    pub fn verb(self) -> &'static str {
+        match self {
+            Mode::Move => "Moved",
+            Mode::Copy => "Copied",
+            Mode::Hardlink => "Hard-linked",
+            Mode::Symlink => "Symlinked",
+            Mode::Reflink => "Cloned",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Moved {
+    /// Same filesystem: a plain rename.
+    Renamed,
+    /// Different filesystem: copied, then the source was deleted. In the
+    /// copy and reflink modes, a plain copy.
+    Copied,
+    /// A hard link or symlink to the source.
+    Linked,
+    /// A reflink sharing the source's storage.
+    Cloned,
+}
+
+/// Move `src` to `dst`, replacing `dst` if it exists. With `verify`, a
+/// cross-filesystem copy is re-read and compared before the source is
+/// deleted.
+pub fn move_file(fs: &dyn FileSystem, src: &Path, dst: &Path, verify: bool) -> io::Result<Moved> {
+    let meta = fs
+        .symlink_metadata(src)
+        .map_err(failure::at(src, "metadata"))?;
+    if meta.is_symlink() {
+        return move_link(fs, src, dst);
+    }
+    match fs.rename(src, dst) {
+        Ok(()) => Ok(Moved::Renamed),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            copy_then_delete(fs, src, dst, verify)?;
+            Ok(Moved::Copied)
+        }
+        Err(e) => Err(failure::at(src, "rename")(e)),
+    }
+}
+
+/// Put a copy or link of `src` at `dst` as `mode` says, replacing `dst` if
+/// it exists. Only `Mode::Move` takes `src` away. With `verify`, copies are
+/// re-read and compared.
+pub fn place(
+    fs: &dyn FileSystem,
+    src: &Path,
+    dst: &Path,
+    mode: Mode,
+    verify: bool,
+) -> io::Result<Moved> {
+    let meta = fs
+        .symlink_metadata(src)
+        .map_err(failure::at(src, "metadata"))?;
+    match mode {
+        Mode::Move => move_file(fs, src, dst, verify),
+        Mode::Hardlink => {
+            into_place(fs, dst, |tmp| {
+                fs.hard_link(src, tmp)
+                    .map_err(failure::at(src, "hard_link"))
+            })?;
+            Ok(Moved::Linked)
+        }
+        Mode::Symlink => {
+            let target = std::path::absolute(src)?;
+            into_place(fs, dst, |tmp| {
+                fs.symlink_file(&target, tmp)
+                    .map_err(failure::at(dst, "symlink"))
+            })?;
+            Ok(Moved::Linked)
+        }
+        // Copying a link copies the link, as moving one does.
+        Mode::Copy | Mode::Reflink if meta.is_symlink() => {
+            copy_link(fs, src, dst)?;
+            Ok(Moved::Copied)
+        }
+        Mode::Copy => {
+            copy_into_place(fs, src, dst, verify)?;
+            Ok(Moved::Copied)
+        }
+        Mode::Reflink => match into_place(fs, dst, |tmp| fs.reflink(src, tmp)) {
+            Ok(()) => Ok(Moved::Cloned),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::Unsupported | io::ErrorKind::CrossesDevices
+                ) =>
+            {
+                copy_into_place(fs, src, dst, verify)?;
+                Ok(Moved::Copied)
+            }
+            Err(e) => Err(failure::at(src, "reflink")(e)),
+        },
+    }
+}
+
+fn copy_then_delete(fs: &dyn FileSystem, src: &Path, dst: &Path, verify: bool) -> io::Result<()> {
+    copy_into_place(fs, src, dst, verify)?;
+    fs.remove_file(src).map_err(failure::at(src, "remove"))
+}
+
+fn copy_into_place(fs: &dyn FileSystem, src: &Path, dst: &Path, verify: bool) -> io::Result<()> {
+    into_place(fs, dst, |tmp| {
+        fs.copy_file(src, tmp)
+            .map_err(failure::at(src, "copy"))
+            .and_then(|()| verify_copy(fs, src, tmp, verify).map_err(failure::at(src, "verify")))
+    })
+}
+
+/// Have `create` make a file at a temporary name beside `dst`, then rename
+/// it over `dst`. The temporary is removed if either step fails.
+fn into_place(
+    fs: &dyn FileSystem,
+    dst: &Path,
+    create: impl FnOnce(&Path) -> io::Result<()>,
+) -> io::Result<()> {
+    let tmp = partial_path(dst);
+    let created =
+        create(&tmp).and_then(|()| fs.rename(&tmp, dst).map_err(failure::at(dst, "rename")));
+    if created.is_err() {
+        let _ = fs.remove_file(&tmp);
+    }
+    created
+}
+
+fn move_link(fs: &dyn FileSystem, src: &Path, dst: &Path) -> io::Result<Moved> {
+    let target = fs.read_link(src).map_err(failure::at(src, "read_link"))?;
+    let relink = target.is_relative() && src.parent() != dst.parent();
+    if !relink {
+        match fs.rename(src, dst) {
+            Ok(()) => return Ok(Moved::Renamed),
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+            Err(e) => return Err(failure::at(src, "rename")(e)),
+        }
+    }
+
+    copy_link(fs, src, dst)?;
+    fs.remove_file(src).map_err(failure::at(src, "remove"))?;
+    Ok(if relink {
+        Moved::Renamed
+    } else {
+        Moved::Copied
+    })
+}
+
+/// Make a link at `dst` pointing where the link at `src` does.
+fn copy_link(fs: &dyn FileSystem, src: &Path, dst: &Path) -> io::Result<()> {
+    let target = fs.read_link(src).map_err(failure::at(src, "read_link"))?;
+    let target = match src.parent() {
+        Some(dir) if target.is_relative() && src.parent() != dst.parent() => {
+            std::path::absolute(dir.join(&target))?
+        }
+        _ => target,
+    };
+    into_place(fs, dst, |tmp| {
+        fs.symlink_file(&target, tmp)
+            .map_err(failure::at(dst, "symlink"))
+    })
+}
+
+/// `dir/name` -> `dir/.name.partial-<pid>`
+pub fn partial_path(dst: &Path) -> PathBuf {
+    let name = dst
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    dst.with_file_name(format!(".{}.partial-{}", name, std::process::id()))
+}
+
+fn verify_copy(fs: &dyn FileSystem, src: &Path, copy: &Path, verify: bool) -> io::Result<()> {
+    if verify && !same_contents(fs, src, copy)? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("copy of {:?} does not match the original", src),
+        ));
+    }
+    Ok(())
+}
+
+/// Byte-for-byte comparison of two files.
+pub fn same_contents(fs: &dyn FileSystem, a: &Path, b: &Path) -> io::Result<bool> {
+    if fs.metadata(a)?.len != fs.metadata(b)?.len {
+        return Ok(false);
+    }
+
+    let mut fa = fs.open(a)?;
+    let mut fb = fs.open(b)?;
+    let mut buf_a = vec![0u8; 64 * 1024];
+    let mut buf_b = vec![0u8; 64 * 1024];
+    loop {
+        let n = fa.read(&mut buf_a)?;
+        if n == 0 {
+            // Same length, so `b` is exhausted too.
+            return Ok(true);
+        }
+        fb.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
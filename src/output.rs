@@ -0,0 +1,397 @@
+// Renderings of a scan `Report`: a human-readable summary plus
+// machine-readable formats.
+//
+// The JSON, NDJSON and CSV layouts are a public contract: scripts and
+// dashboards parse them. Add fields freely, but bump `SCHEMA_VERSION` for
+// anything that renames, removes or changes the meaning of existing ones.
+
+use crate::scan::{Report, SIZE_BUCKET_LABELS};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::path::Path;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable summary
+    #[default]
+    Text,
+    /// A single JSON document
+    Json,
+    /// One JSON record per line
+    Ndjson,
+    /// Flat CSV rows with a header
+    Csv,
+}
+
+#[derive(Serialize)]
+struct ScanDocument<'a> {
+    schema_version: u32,
+    folder: String,
+    #[serde(flatten)]
+    summary: Summary,
+    by_extension: Vec<ExtensionRecord<'a>>,
+    by_directory: Vec<DirectoryRecord>,
+    largest: Vec<FileRecord>,
+    size_histogram: Vec<BucketRecord>,
+    mismatches: Vec<MismatchRecord>,
+}
+
+#[derive(Serialize)]
+struct Summary {
+    total_entries: usize,
+    files: usize,
+    dirs: usize,
+    hidden_files: usize,
+    symlinks: usize,
+    broken_symlinks: usize,
+    total_bytes: u64,
+}
+
+#[derive(Serialize)]
+struct ExtensionRecord<'a> {
+    extension: &'a str,
+    files: usize,
+    bytes: u64,
+}
+
+#[derive(Serialize)]
+struct DirectoryRecord {
+    path: String,
+    files: usize,
+    dirs: usize,
+    bytes: u64,
+}
+
+#[derive(Serialize)]
+struct FileRecord {
+    path: String,
+    bytes: u64,
+}
+
+#[derive(Serialize)]
+struct BucketRecord {
+    bucket: &'static str,
+    files: usize,
+}
+
+#[derive(Serialize)]
+struct MismatchRecord {
+    path: String,
+    extension: String,
+    content: &'static str,
+}
+
+/// One NDJSON line. The `record` tag tells consumers which shape follows.
+#[derive(Serialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+enum NdjsonRecord<'a> {
+    Summary {
+        schema_version: u32,
+        folder: String,
+        #[serde(flatten)]
+        summary: Summary,
+    },
+    Extension(ExtensionRecord<'a>),
+    Directory(DirectoryRecord),
+    Largest(FileRecord),
+    SizeBucket(BucketRecord),
+    Mismatch(MismatchRecord),
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn summary(report: &Report) -> Summary {
+    Summary {
+        total_entries: report.total_entries,
+        files: report.files,
+        dirs: report.dirs,
+        hidden_files: report.hidden_files,
+        symlinks: report.symlinks,
+        broken_symlinks: report.broken_symlinks,
+        total_bytes: report.total_bytes,
+    }
+}
+
+fn extensions(report: &Report) -> impl Iterator<Item = ExtensionRecord<'_>> {
+    report
+        .by_extension
+        .iter()
+        .map(|(ext, totals)| ExtensionRecord {
+            extension: ext,
+            files: totals.files,
+            bytes: totals.bytes,
+        })
+}
+
+fn directories(report: &Report) -> impl Iterator<Item = DirectoryRecord> + '_ {
+    report.by_dir.iter().map(|(dir, totals)| DirectoryRecord {
+        path: path_string(dir),
+        files: totals.files,
+        dirs: totals.dirs,
+        bytes: totals.bytes,
+    })
+}
+
+fn largest(report: &Report) -> impl Iterator<Item = FileRecord> + '_ {
+    report.largest.iter().map(|(path, size)| FileRecord {
+        path: path_string(path),
+        bytes: *size,
+    })
+}
+
+fn buckets(report: &Report) -> impl Iterator<Item = BucketRecord> + '_ {
+    SIZE_BUCKET_LABELS
+        .iter()
+        .zip(&report.size_histogram)
+        .map(|(label, count)| BucketRecord {
+            bucket: label,
+            files: *count,
+        })
+}
+
+fn mismatches(report: &Report) -> impl Iterator<Item = MismatchRecord> + '_ {
+    report.mismatches.iter().map(|m| MismatchRecord {
+        path: path_string(&m.path),
+        extension: m.extension.clone(),
+        content: m.content,
+    })
+}
+
+pub fn write_text(out: &mut impl Write, folder: &Path, report: &Report) -> io::Result<()> {
+    writeln!(out, "Folder: {:?}", folder)?;
+    writeln!(out, "Total entries: {}", report.total_entries)?;
+    writeln!(out, "Files: {}", report.files)?;
+    writeln!(out, "Dirs: {}", report.dirs)?;
+    if report.hidden_files > 0 {
+        writeln!(out, "Hidden files: {}", report.hidden_files)?;
+    }
+    if report.symlinks > 0 {
+        writeln!(
+            out,
+            "Symlinks: {} ({} broken)",
+            report.symlinks, report.broken_symlinks
+        )?;
+    }
+    writeln!(out, "Total size: {}", human_size(report.total_bytes))?;
+    writeln!(out, "\nFiles by extension:")?;
+
+    if report.by_extension.is_empty() {
+        return writeln!(out, "  (none)");
+    }
+
+    for (ext, totals) in &report.by_extension {
+        writeln!(
+            out,
+            "  {:>8}  {:>10}  {}",
+            totals.files,
+            human_size(totals.bytes),
+            ext
+        )?;
+    }
+
+    writeln!(out, "\nSize histogram:")?;
+    for (label, count) in SIZE_BUCKET_LABELS.iter().zip(&report.size_histogram) {
+        writeln!(out, "  {:>8}  {}", count, label)?;
+    }
+
+    if !report.largest.is_empty() {
+        writeln!(out, "\nLargest files:")?;
+        for (path, size) in &report.largest {
+            writeln!(out, "  {:>10}  {}", human_size(*size), path.display())?;
+        }
+    }
+
+    if !report.mismatches.is_empty() {
+        writeln!(out, "\nExtension contradicts content:")?;
+        for m in &report.mismatches {
+            writeln!(
+                out,
+                "  {}  (.{} but looks like {})",
+                m.path.display(),
+                m.extension,
+                m.content
+            )?;
+        }
+    }
+
+    // Subtotals are only interesting once more than one directory was seen.
+    if report.by_dir.len() > 1 {
+        writeln!(out, "\nBy directory (files / dirs / size):")?;
+        for (dir, totals) in &report.by_dir {
+            writeln!(
+                out,
+                "  {:>8}  {:>6}  {:>10}  {}",
+                totals.files,
+                totals.dirs,
+                human_size(totals.bytes),
+                dir.display()
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Format a byte count using binary units, e.g. `1.5 MiB`.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+pub fn write_json(out: &mut impl Write, folder: &Path, report: &Report) -> io::Result<()> {
+    let doc = ScanDocument {
+        schema_version: SCHEMA_VERSION,
+        folder: path_string(folder),
+        summary: summary(report),
+        by_extension: extensions(report).collect(),
+        by_directory: directories(report).collect(),
+        largest: largest(report).collect(),
+        size_histogram: buckets(report).collect(),
+        mismatches: mismatches(report).collect(),
+    };
+    serde_json::to_writer_pretty(&mut *out, &doc)?;
+    writeln!(out)
+}
+
+pub fn write_ndjson(out: &mut impl Write, folder: &Path, report: &Report) -> io::Result<()> {
+    let records = std::iter::once(NdjsonRecord::Summary {
+        schema_version: SCHEMA_VERSION,
+        folder: path_string(folder),
+        summary: summary(report),
+    })
+    .chain(extensions(report).map(NdjsonRecord::Extension))
+    .chain(directories(report).map(NdjsonRecord::Directory))
+    .chain(largest(report).map(NdjsonRecord::Largest))
+    .chain(buckets(report).map(NdjsonRecord::SizeBucket))
+    .chain(mismatches(report).map(NdjsonRecord::Mismatch));
+
+    for record in records {
+        serde_json::to_writer(&mut *out, &record)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// CSV columns: `schema_version,record,name,files,dirs,bytes,extension,content`.
+/// Columns that don't apply to a record type are left empty.
+pub fn write_csv(out: &mut impl Write, folder: &Path, report: &Report) -> io::Result<()> {
+    writeln!(
+        out,
+        "schema_version,record,name,files,dirs,bytes,extension,content"
+    )?;
+
+    csv_row(
+        out,
+        "summary",
+        &path_string(folder),
+        Some(report.files),
+        Some(report.dirs),
+        Some(report.total_bytes),
+    )?;
+    csv_row(
+        out,
+        "hidden",
+        &path_string(folder),
+        Some(report.hidden_files),
+        None,
+        None,
+    )?;
+    for (record, count) in [
+        ("symlinks", report.symlinks),
+        ("broken_symlinks", report.broken_symlinks),
+    ] {
+        csv_row(out, record, &path_string(folder), Some(count), None, None)?;
+    }
+    for ext in extensions(report) {
+        csv_row(
+            out,
+            "extension",
+            ext.extension,
+            Some(ext.files),
+            None,
+            Some(ext.bytes),
+        )?;
+    }
+    for dir in directories(report) {
+        csv_row(
+            out,
+            "directory",
+            &dir.path,
+            Some(dir.files),
+            Some(dir.dirs),
+            Some(dir.bytes),
+        )?;
+    }
+    for file in largest(report) {
+        csv_row(out, "largest", &file.path, None, None, Some(file.bytes))?;
+    }
+    for bucket in buckets(report) {
+        csv_row(
+            out,
+            "size_bucket",
+            bucket.bucket,
+            Some(bucket.files),
+            None,
+            None,
+        )?;
+    }
+    for m in mismatches(report) {
+        writeln!(
+            out,
+            "{},mismatch,{},,,,{},{}",
+            SCHEMA_VERSION,
+            csv_field(&m.path),
+            csv_field(&m.extension),
+            m.content
+        )?;
+    }
+    Ok(())
+}
+
+fn csv_row(
+    out: &mut impl Write,
+    record: &str,
+    name: &str,
+    files: Option<usize>,
+    dirs: Option<usize>,
+    bytes: Option<u64>,
+) -> io::Result<()> {
+    fn cell(value: Option<impl ToString>) -> String {
+        value.map(|v| v.to_string()).unwrap_or_default()
+    }
+
+    writeln!(
+        out,
+        "{},{},{},{},{},{},,",
+        SCHEMA_VERSION,
+        record,
+        csv_field(name),
+        cell(files),
+        cell(dirs),
+        cell(bytes),
+    )
+}
+
+/// Quote a CSV field per RFC 4180 when it contains a delimiter, quote or newline.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
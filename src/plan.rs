@@ -0,0 +1,474 @@
+// Deciding where files go, without touching them.
+//
+// A `Planner` looks at the top level of a folder, or with `recursive` the
+// whole tree, and produces a `Plan`: one move per file, from where it is to
+// where its classifier says it belongs. The destination is never walked.
+// Conflicts with files already at the destination are left to the executor,
+// which sees the tree as it is when the moves happen.
+//
+// A plan can be saved as JSON, reviewed or edited, and applied later. Each
+// move records the size and mtime its source had when planned, so a stale
+// plan is caught before anything moves.
+
+use crate::classify::{Classifier, FileInfo};
+use crate::failure::{self, Failure};
+use crate::filter::Filter;
+use crate::journal::{JOURNAL_FILE, nanos_since_epoch};
+use crate::mover::Mode;
+use crate::scan::{self, LinkStatus, Scanner, Symlinks, WalkOptions};
+use crate::sniff::{self, ClassifyBy};
+use crate::vfs::{FileSystem, Metadata};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Bump when the saved plan layout changes incompatibly.
+pub const PLAN_SCHEMA_VERSION: u32 = 2;
+
+/// A file to move, with the destination its classifier chose.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlannedMove {
+    pub src: PathBuf,
+    pub dst: PathBuf,
+    /// Size and mtime of `src` when the move was planned.
+    pub size: u64,
+    pub mtime_ns: u64,
+    /// `src` is a symlink moved as a link; `size` and `mtime_ns` are the
+    /// link's own.
+    #[serde(default)]
+    pub link: bool,
+}
+
+/// Moves into an organized root, in the order they should happen.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Plan {
+    /// Root the organized tree is built under.
+    pub root: PathBuf,
+    pub moves: Vec<PlannedMove>,
+    /// What carrying out the moves does with each file. Plans come out of
+    /// the planner as `Mode::Move`; `organize --mode` sets it before saving.
+    pub mode: Mode,
+    /// Files that couldn't be looked at, with `Planner::keep_going`. Not
+    /// saved with the plan.
+    #[serde(skip)]
+    pub failures: Vec<Failure>,
+}
+
+/// Where files from subfolders go in a recursive plan.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Layout {
+    /// Straight into their category folder
+    #[default]
+    Flatten,
+    /// Into their category folder, under the subfolders they were in
+    Mirror,
+}
+
+pub struct Planner {
+    pub classifier: Box<dyn Classifier>,
+    /// Root the organized tree is built under.
+    pub dest: PathBuf,
+    pub classify_by: ClassifyBy,
+    /// Files to leave where they are.
+    pub filter: Filter,
+    pub symlinks: Symlinks,
+    /// Look at files in subfolders too.
+    pub recursive: bool,
+    pub layout: Layout,
+    /// Note files that can't be looked at in `Plan::failures` and carry on.
+    pub keep_going: bool,
+}
+
+impl Planner {
+    /// Plan moves for the files at the top level of `folder`, or everywhere
+    /// under it with `recursive`.
+    pub fn plan(&self, fs: &dyn FileSystem, folder: &Path) -> io::Result<Plan> {
+        let now = SystemTime::now();
+
+        // The destination may live inside `folder` under any name; recognise
+        // it by its real path. If it doesn't exist yet there's nothing to skip.
+        let dest_real = fs.canonicalize(&self.dest).ok();
+        // Organizing a folder into itself: the walk starts inside the
+        // destination, so files already in their category folder stay put.
+        let into_itself = dest_real.is_some() && fs.canonicalize(folder).ok() == dest_real;
+
+        let walk = WalkOptions {
+            follow_links: self.symlinks == Symlinks::Follow,
+            ..if self.recursive {
+                WalkOptions::recursive()
+            } else {
+                WalkOptions::top_level()
+            }
+        };
+        let mut scanner = Scanner::with_filter(fs, folder, &walk, &self.filter)?;
+        if let Some(real) = &dest_real {
+            scanner = scanner.pruning(real.clone());
+        }
+        let mut moves = Vec::new();
+        let mut failures = Vec::new();
+        for entry in scanner {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if self.keep_going && Failure::of(&e).is_some() => {
+                    failures.push(Failure::from_error(&e, folder, "plan"));
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            // Skip the destination tree itself and any directories.
+            if dest_real.is_some() && fs.canonicalize(&entry.path).ok() == dest_real {
+                continue;
+            }
+            if !self.movable(&entry.meta, entry.link) {
+                continue;
+            }
+            let subpath = entry.rel_dir.strip_prefix(".").unwrap_or(&entry.rel_dir);
+            if into_itself
+                && subpath.as_os_str().is_empty()
+                && entry.path.file_name() == Some(JOURNAL_FILE.as_ref())
+            {
+                continue;
+            }
+            let placed = into_itself.then_some(subpath);
+            moves.extend(self.planned_move(
+                fs,
+                entry.path.clone(),
+                subpath,
+                placed,
+                &entry.meta,
+                now,
+            ));
+        }
+
+        Ok(Plan {
+            root: self.dest.clone(),
+            moves,
+            mode: Mode::Move,
+            failures,
+        })
+    }
+
+    /// Plan moves for just these files, e.g. ones that just arrived. Paths
+    /// that are gone, aren't files or are filtered out are left out; each is
+    /// filtered as if at the top level of the folder it is in.
+    pub fn plan_files(&self, fs: &dyn FileSystem, paths: &[PathBuf]) -> io::Result<Plan> {
+        let now = SystemTime::now();
+        let mut moves = Vec::new();
+        let mut failures = Vec::new();
+        for path in paths {
+            let follow = self.symlinks == Symlinks::Follow;
+            let (meta, link) = match scan::link_metadata(fs, path, follow) {
+                Ok(found) => found,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) if self.keep_going => {
+                    failures.push(Failure::from_error(&e, path, "metadata"));
+                    continue;
+                }
+                Err(e) => return Err(failure::at(path, "metadata")(e)),
+            };
+            if !self.movable(&meta, link) {
+                continue;
+            }
+            let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+                continue;
+            };
+            let ignores = self
+                .filter
+                .ignores_in(fs, dir, Path::new(""))
+                .map_err(failure::at(dir, "read ignores"))?;
+            if self.filter.excludes(Path::new(name), false, &ignores) {
+                continue;
+            }
+            moves.extend(self.planned_move(fs, path.clone(), Path::new(""), None, &meta, now));
+        }
+
+        Ok(Plan {
+            root: self.dest.clone(),
+            moves,
+            mode: Mode::Move,
+            failures,
+        })
+    }
+
+    /// Whether an entry is something to move: a file, or a symlink the
+    /// policy says to move.
+    fn movable(&self, meta: &Metadata, link: LinkStatus) -> bool {
+        match (link, self.symlinks) {
+            (LinkStatus::NotLink, _) => meta.is_file(),
+            (_, Symlinks::Skip) => false,
+            // A live link to a file carries the file's metadata.
+            (_, Symlinks::Follow) => meta.is_file(),
+            (_, Symlinks::MoveLink) => true,
+        }
+    }
+
+    /// The move for the file at `path`, which is `subpath` below the folder
+    /// being organized. `placed` is where the file is within the destination
+    /// when the folder is its own destination; a file already in its category
+    /// folder there, or already at its destination, has no move.
+    fn planned_move(
+        &self,
+        fs: &dyn FileSystem,
+        path: PathBuf,
+        subpath: &Path,
+        placed: Option<&Path>,
+        meta: &Metadata,
+        now: SystemTime,
+    ) -> Option<PlannedMove> {
+        let ext = sniff::effective_ext(fs, &path, self.classify_by);
+        let file = FileInfo {
+            fs,
+            path: &path,
+            ext: ext.as_deref(),
+            meta,
+            now,
+        };
+        // `None` leaves the file alone, e.g. no rule matched and there is
+        // no fallback.
+        let category = self.classifier.classify(&file)?;
+        if placed.is_some_and(|placed| placed.starts_with(&category)) {
+            return None;
+        }
+        let mut dest_dir = self.dest.join(category);
+        if self.layout == Layout::Mirror {
+            dest_dir.push(subpath);
+        }
+        let file_name = path.file_name()?;
+        let dst = dest_dir.join(file_name);
+        if dst == path {
+            return None;
+        }
+
+        Some(PlannedMove {
+            src: path,
+            dst,
+            size: meta.len,
+            mtime_ns: meta.modified.map(nanos_since_epoch).unwrap_or(0),
+            link: meta.is_symlink(),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct PlanDocument<'a> {
+    schema_version: u32,
+    #[serde(flatten)]
+    plan: &'a Plan,
+}
+
+#[derive(Deserialize)]
+struct PlanFile {
+    schema_version: u32,
+    #[serde(flatten)]
+    plan: Plan,
+}
+
+impl Plan {
+    /// Save the plan as JSON, with absolute paths so it can be applied from
+    /// any working directory.
+    pub fn write_json(&self, out: &mut impl Write) -> io::Result<()> {
+        let absolute = Plan {
+            root: std::path::absolute(&self.root)?,
+            mode: self.mode,
+            moves: self
+                .moves
+                .iter()
+                .map(|m| {
+                    Ok(PlannedMove {
+                        src: std::path::absolute(&m.src)?,
+                        dst: std::path::absolute(&m.dst)?,
+                        ..m.clone()
+                    })
+                })
+                .collect::<io::Result<_>>()?,
+            failures: Vec::new(),
+        };
+        let doc = PlanDocument {
+            schema_version: PLAN_SCHEMA_VERSION,
+            plan: &absolute,
+        };
+        serde_json::to_writer_pretty(&mut *out, &doc)?;
+        writeln!(out)
+    }
+
+    pub fn read_json(input: impl Read) -> io::Result<Plan> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let file: PlanFile =
+            serde_json::from_reader(input).map_err(|e| invalid(format!("plan: {}", e)))?;
+        if file.schema_version != PLAN_SCHEMA_VERSION {
+            return Err(invalid(format!(
+                "plan has schema version {}, expected {}",
+                file.schema_version, PLAN_SCHEMA_VERSION
+            )));
+        }
+        Ok(file.plan)
+    }
+
+    /// Sources that are gone or no longer match the size and mtime they had
+    /// when planned, with the reason.
+    pub fn stale_sources(&self, fs: &dyn FileSystem) -> Vec<(PathBuf, String)> {
+        self.moves
+            .iter()
+            .filter_map(|m| {
+                // A link is checked as itself, since that is what moves.
+                let meta = if m.link {
+                    fs.symlink_metadata(&m.src)
+                } else {
+                    fs.metadata(&m.src)
+                };
+                let reason = match meta {
+                    Ok(meta) if m.link && !meta.is_symlink() => "no longer a symlink".to_string(),
+                    Ok(meta) if !m.link && !meta.is_file() => "no longer a file".to_string(),
+                    Ok(meta)
+                        if meta.len != m.size
+                            || meta.modified.map(nanos_since_epoch).unwrap_or(0) != m.mtime_ns =>
+                    {
+                        "changed since it was planned".to_string()
+                    }
+                    Ok(_) => return None,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        "deleted or moved since it was planned".to_string()
+                    }
+                    Err(e) => e.to_string(),
+                };
+                Some((m.src.clone(), reason))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::classify::ByExtension;
+    use crate::vfs::MemoryFs;
+
+    /// A planner for the top level of a folder, by extension into `dest`.
+    pub(crate) fn planner(dest: &str) -> Planner {
+        Planner {
+            classifier: Box::new(ByExtension),
+            dest: PathBuf::from(dest),
+            classify_by: ClassifyBy::Extension,
+            filter: Filter::default(),
+            symlinks: Symlinks::Skip,
+            recursive: false,
+            layout: Layout::Flatten,
+            keep_going: false,
+        }
+    }
+
+    /// Save and reload, as `organize --plan-out` and `apply` do.
+    fn round_trip(plan: &Plan) -> Plan {
+        let mut json = Vec::new();
+        plan.write_json(&mut json).unwrap();
+        Plan::read_json(json.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn moved_links_are_not_stale() {
+        let fs = MemoryFs::new();
+        fs.write_file("/target.txt", "a longer target file")
+            .unwrap();
+        fs.create_dir_all(Path::new("/in")).unwrap();
+        fs.symlink("/target.txt", "/in/live.txt").unwrap();
+        fs.symlink("/missing.txt", "/in/broken.txt").unwrap();
+
+        let planner = Planner {
+            symlinks: Symlinks::MoveLink,
+            ..planner("/in/organized")
+        };
+        let plan = round_trip(&planner.plan(&fs, Path::new("/in")).unwrap());
+        assert_eq!(plan.moves.len(), 2);
+        assert!(plan.moves.iter().all(|m| m.link));
+        assert!(plan.stale_sources(&fs).is_empty());
+
+        fs.remove_file(Path::new("/in/live.txt")).unwrap();
+        fs.write_file("/in/live.txt", "now a file").unwrap();
+        let stale = plan.stale_sources(&fs);
+        assert_eq!(
+            stale,
+            [(
+                PathBuf::from("/in/live.txt"),
+                "no longer a symlink".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn followed_links_are_checked_through_the_link() {
+        let fs = MemoryFs::new();
+        fs.write_file("/target.txt", "data").unwrap();
+        fs.create_dir_all(Path::new("/in")).unwrap();
+        fs.symlink("/target.txt", "/in/live.txt").unwrap();
+
+        let planner = Planner {
+            symlinks: Symlinks::Follow,
+            ..planner("/in/organized")
+        };
+        let plan = planner.plan(&fs, Path::new("/in")).unwrap();
+        assert!(!plan.moves[0].link);
+        assert!(plan.stale_sources(&fs).is_empty());
+
+        fs.write_file("/target.txt", "changed data").unwrap();
+        assert_eq!(plan.stale_sources(&fs).len(), 1);
+    }
+
+    #[test]
+    fn plans_without_the_link_field_still_load() {
+        let json = r#"{"schema_version": 2, "root": "/o", "mode": "move", "moves": [
+            {"src": "/a.txt", "dst": "/o/txt/a.txt", "size": 1, "mtime_ns": 2}
+        ]}"#;
+        let plan = Plan::read_json(json.as_bytes()).unwrap();
+        assert!(!plan.moves[0].link);
+    }
+
+    #[test]
+    fn plans_without_a_mode_are_refused() {
+        let json = r#"{"schema_version": 1, "root": "/o", "moves": []}"#;
+        let err = Plan::read_json(json.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_folder_organized_into_itself_stays_organized() {
+        for layout in [Layout::Flatten, Layout::Mirror] {
+            let fs = MemoryFs::new();
+            fs.write_file("/in/a.txt", "a").unwrap();
+            fs.write_file("/in/deep/b.png", "b").unwrap();
+            let planner = Planner {
+                dest: PathBuf::from("/in"),
+                recursive: true,
+                layout,
+                ..planner("/in")
+            };
+
+            let plan = planner.plan(&fs, Path::new("/in")).unwrap();
+            assert_eq!(plan.moves.len(), 2);
+            for m in &plan.moves {
+                fs.create_dir_all(m.dst.parent().unwrap()).unwrap();
+                fs.rename(&m.src, &m.dst).unwrap();
+            }
+            fs.append(&Path::new("/in").join(JOURNAL_FILE), b"{}\n")
+                .unwrap();
+
+            let again = planner.plan(&fs, Path::new("/in")).unwrap();
+            assert!(again.moves.is_empty(), "{:?}: {:?}", layout, again.moves);
+        }
+    }
+
+    #[test]
+    fn saved_plans_keep_their_mode() {
+        let fs = MemoryFs::new();
+        fs.write_file("/in/a.txt", "a").unwrap();
+        let mut plan = planner("/in/organized")
+            .plan(&fs, Path::new("/in"))
+            .unwrap();
+        assert_eq!(plan.mode, Mode::Move);
+
+        plan.mode = Mode::Hardlink;
+        assert_eq!(round_trip(&plan).mode, Mode::Hardlink);
+    }
+}
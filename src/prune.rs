@@ -0,0 +1,263 @@
+// Removing empty directories for the `prune-empty` subcommand.
+//
+// A directory counts as empty when all it holds is empty directories and the
+// files operating systems leave behind (`JUNK_FILES`). The tree is walked
+// bottom-up, so a directory left holding only empty ones goes as well.
+//
+// Some directories are never removed: the folder itself, protected
+// directories (which aren't even looked inside) and dot-directories such as
+// `.git`, where tools expect empty directories to stay.
+
+use crate::failure::{self, Failure};
+use crate::vfs::FileSystem;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Files that don't stop a directory from counting as empty. Matched
+/// without regard to case, as on the systems that create them.
+pub const JUNK_FILES: [&str; 3] = [".DS_Store", "Thumbs.db", "desktop.ini"];
+
+/// A directory `run` removed, or in a dry run would remove.
+#[derive(Debug)]
+pub struct Removed {
+    pub dir: PathBuf,
+    /// Junk files removed with it.
+    pub junk: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Outcome {
+    /// The folder itself is protected, so nothing was looked at.
+    pub protected: bool,
+    pub removed: usize,
+    pub junk: usize,
+    /// Directories that couldn't be looked at or removed.
+    pub failures: Vec<Failure>,
+}
+
+pub struct PruneOptions {
+    /// Directories to leave alone, with everything in them. Each must exist.
+    pub protected: Vec<PathBuf>,
+    pub dry_run: bool,
+}
+
+/// Remove the empty directories under `folder`, deepest first, passing each
+/// to `on_removed`.
+pub fn run(
+    fs: &dyn FileSystem,
+    folder: &Path,
+    opts: &PruneOptions,
+    on_removed: impl FnMut(Removed),
+) -> io::Result<Outcome> {
+    // Compared by real path, so any spelling of a protected directory works.
+    // One that doesn't exist is most likely a typo, so it is an error rather
+    // than protecting nothing.
+    let protected = opts
+        .protected
+        .iter()
+        .map(|dir| fs.canonicalize(dir).map_err(failure::at(dir, "protect")))
+        .collect::<io::Result<_>>()?;
+    let mut pruner = Pruner {
+        fs,
+        protected,
+        dry_run: opts.dry_run,
+        on_removed,
+        outcome: Outcome::default(),
+    };
+    if pruner.is_protected(folder)? {
+        pruner.outcome.protected = true;
+        return Ok(pruner.outcome);
+    }
+    for path in fs.read_dir(folder)? {
+        pruner.visit(&path);
+    }
+    Ok(pruner.outcome)
+}
+
+/// Whether a file's name is in `JUNK_FILES`.
+pub fn is_junk(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    JUNK_FILES
+        .iter()
+        .any(|junk| name.eq_ignore_ascii_case(junk))
+}
+
+struct Pruner<'a, F> {
+    fs: &'a dyn FileSystem,
+    protected: Vec<PathBuf>,
+    dry_run: bool,
+    on_removed: F,
+    outcome: Outcome,
+}
+
+impl<F: FnMut(Removed)> Pruner<'_, F> {
+    /// Look at one entry, removing it if it is an empty directory. Returns
+    /// whether it is gone (or in a dry run, would be).
+    fn visit(&mut self, path: &Path) -> bool {
+        match self.prune(path) {
+            Ok(gone) => gone,
+            Err(e) => {
+                self.outcome
+                    .failures
+                    .push(Failure::from_error(&e, path, "prune"));
+                false
+            }
+        }
+    }
+
+    fn prune(&mut self, path: &Path) -> io::Result<bool> {
+        // Symlinks are never followed; a link is something in the directory.
+        let meta = self
+            .fs
+            .symlink_metadata(path)
+            .map_err(failure::at(path, "metadata"))?;
+        if !meta.is_dir() {
+            return Ok(false);
+        }
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if hidden || self.is_protected(path)? {
+            return Ok(false);
+        }
+
+        let mut empty = true;
+        let mut junk = Vec::new();
+        for entry in self
+            .fs
+            .read_dir(path)
+            .map_err(failure::at(path, "read_dir"))?
+        {
+            if self.visit(&entry) {
+                continue;
+            }
+            match self.fs.symlink_metadata(&entry) {
+                Ok(meta) if meta.is_file() && is_junk(&entry) => junk.push(entry),
+                _ => empty = false,
+            }
+        }
+        if !empty {
+            return Ok(false);
+        }
+
+        if !self.dry_run {
+            for file in &junk {
+                self.fs
+                    .remove_file(file)
+                    .map_err(failure::at(file, "remove"))?;
+            }
+            self.fs
+                .remove_dir(path)
+                .map_err(failure::at(path, "remove_dir"))?;
+        }
+        self.outcome.removed += 1;
+        self.outcome.junk += junk.len();
+        (self.on_removed)(Removed {
+            dir: path.to_path_buf(),
+            junk: junk.len(),
+        });
+        Ok(true)
+    }
+
+    /// Whether `dir` is a protected directory or inside one.
+    fn is_protected(&self, dir: &Path) -> io::Result<bool> {
+        if self.protected.is_empty() {
+            return Ok(false);
+        }
+        let real = self
+            .fs
+            .canonicalize(dir)
+            .map_err(failure::at(dir, "canonicalize"))?;
+        Ok(self.protected.iter().any(|p| real.starts_with(p)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFs;
+
+    fn prune(fs: &MemoryFs, protected: &[&str]) -> (Vec<PathBuf>, Outcome) {
+        let opts = PruneOptions {
+            protected: protected.iter().map(PathBuf::from).collect(),
+            dry_run: false,
+        };
+        let mut removed = Vec::new();
+        let outcome = run(fs, Path::new("/d"), &opts, |r| removed.push(r.dir)).unwrap();
+        (removed, outcome)
+    }
+
+    #[test]
+    fn folders_holding_only_junk_go_with_it() {
+        let fs = MemoryFs::new();
+        fs.write_file("/d/photos/.DS_Store", "").unwrap();
+        fs.write_file("/d/photos/2024/thumbs.db", "").unwrap();
+        fs.write_file("/d/docs/notes.txt", "keep").unwrap();
+        fs.write_file("/d/docs/desktop.ini", "").unwrap();
+
+        let (removed, outcome) = prune(&fs, &[]);
+
+        assert_eq!(
+            removed,
+            [PathBuf::from("/d/photos/2024"), PathBuf::from("/d/photos")]
+        );
+        assert_eq!(outcome.junk, 2);
+        assert!(fs.symlink_metadata(Path::new("/d/photos")).is_err());
+        // Junk beside a real file stays.
+        assert!(
+            fs.symlink_metadata(Path::new("/d/docs/desktop.ini"))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn nothing_inside_a_protected_folder_is_removed() {
+        let fs = MemoryFs::new();
+        fs.create_dir_all(Path::new("/d/keep/work/empty")).unwrap();
+        fs.create_dir_all(Path::new("/d/gone")).unwrap();
+
+        let (removed, _) = prune(&fs, &["/d/keep"]);
+
+        assert_eq!(removed, [PathBuf::from("/d/gone")]);
+        assert!(fs.symlink_metadata(Path::new("/d/keep/work/empty")).is_ok());
+    }
+
+    #[test]
+    fn a_folder_inside_a_protected_one_is_left_alone() {
+        let fs = MemoryFs::new();
+        fs.create_dir_all(Path::new("/d/empty")).unwrap();
+
+        let (removed, outcome) = prune(&fs, &["/"]);
+
+        assert!(outcome.protected);
+        assert!(removed.is_empty());
+        assert!(fs.symlink_metadata(Path::new("/d/empty")).is_ok());
+    }
+
+    #[test]
+    fn dot_directories_stay() {
+        let fs = MemoryFs::new();
+        fs.create_dir_all(Path::new("/d/.git/refs")).unwrap();
+        fs.create_dir_all(Path::new("/d/src/.cache")).unwrap();
+
+        let (removed, _) = prune(&fs, &[]);
+
+        assert!(removed.is_empty());
+        assert!(fs.symlink_metadata(Path::new("/d/.git/refs")).is_ok());
+        assert!(fs.symlink_metadata(Path::new("/d/src/.cache")).is_ok());
+    }
+
+    #[test]
+    fn a_protected_folder_must_exist() {
+        let fs = MemoryFs::new();
+        fs.create_dir_all(Path::new("/d")).unwrap();
+        let opts = PruneOptions {
+            protected: vec![PathBuf::from("/d/typo")],
+            dry_run: false,
+        };
+
+        let err = run(&fs, Path::new("/d"), &opts, |_| {}).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("/d/typo"));
+    }
+}
@@ -0,0 +1,520 @@
+// Declarative organize rules loaded from a TOML file.
+//
+//     [[rule]]
+//     name = "screenshots"
+//     glob = "Screenshot*"
+//     extensions = ["png", "jpg"]
+//     dest = "Images/Screenshots/{year}"
+//
+//     [[rule]]
+//     mime = "application/pdf"
+//     min_size = "1M"
+//     max_age = "90d"
+//     dest = "Documents/{year}-{month:02}"
+//
+//     [[rule]]
+//     extensions = ["jpg", "heic"]
+//     dest = "Photos"
+//     by = ["category", { date = "{year}" }]
+//
+//     [fallback]
+//     dest = "Other/{ext}"
+//
+// Rules are tried in file order and the first one whose matchers all agree
+// wins. A rule with no matchers matches everything. Files that match no rule
+// go to `fallback`, or are left alone when there is no fallback.
+//
+// A rule's destination is its `dest` template followed by the folders its
+// `by` classifiers pick, in order. Either may be left out, not both. A rule
+// whose classifiers leave the file alone (a `regex` that doesn't match) is
+// passed over like one whose matchers disagree.
+
+use crate::category::Categories;
+use crate::classify::{ByExtension, ByRegex, Classifier, Composite, FileInfo, SizeBuckets};
+use crate::dates::{self, DateGrouping};
+use crate::template::{Template, civil_date};
+use crate::vfs::FileSystem;
+use regex::Regex;
+use serde::Deserialize;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Placeholders available in a rule's `dest` template.
+pub const PLACEHOLDERS: &[&str] = &["ext", "stem", "name", "year", "month", "day"];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<RuleSpec>,
+    fallback: Option<FallbackSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    name: Option<String>,
+    #[serde(default)]
+    extensions: Vec<String>,
+    glob: Option<String>,
+    regex: Option<String>,
+    mime: Option<String>,
+    min_size: Option<Quantity>,
+    max_size: Option<Quantity>,
+    min_age: Option<Quantity>,
+    max_age: Option<Quantity>,
+    dest: Option<String>,
+    #[serde(default)]
+    by: Vec<ClassifierSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FallbackSpec {
+    dest: Option<String>,
+    #[serde(default)]
+    by: Vec<ClassifierSpec>,
+}
+
+/// An entry of a `by` list: `"extension"`, `"category"`, `"size"`, `"date"`,
+/// `{ date = "{year}" }` or `{ regex = "^IMG_(\\d{4})", dest = "$1" }`.
+#[derive(Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum ClassifierSpec {
+    Name(String),
+    Date { date: String },
+    Regex { regex: String, dest: String },
+}
+
+/// Sizes and ages may be written as bare numbers (bytes / seconds) or as
+/// strings with a unit, e.g. `"10M"` or `"30d"`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Quantity {
+    Number(u64),
+    Text(String),
+}
+
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    fallback: Option<Target>,
+}
+
+/// Where a rule or the fallback sends a file.
+struct Target {
+    dest: Option<Template>,
+    by: Option<Composite>,
+}
+
+struct Rule {
+    extensions: Vec<String>,
+    glob: Option<glob::Pattern>,
+    regex: Option<Regex>,
+    mime: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    min_age: Option<Duration>,
+    max_age: Option<Duration>,
+    target: Target,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl RuleSet {
+    pub fn load(fs: &dyn FileSystem, path: &Path) -> io::Result<RuleSet> {
+        let mut text = String::new();
+        fs.open(path)?.read_to_string(&mut text)?;
+        let file: RulesFile =
+            toml::from_str(&text).map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+
+        let rules = file
+            .rule
+            .into_iter()
+            .enumerate()
+            .map(|(i, spec)| Rule::compile(i + 1, spec))
+            .collect::<io::Result<Vec<_>>>()?;
+        let fallback = file
+            .fallback
+            .map(|f| Target::compile("fallback", f.dest, f.by))
+            .transpose()?;
+
+        Ok(RuleSet { rules, fallback })
+    }
+}
+
+impl Classifier for RuleSet {
+    /// `None` means no rule matched and there is no fallback.
+    fn classify(&self, file: &FileInfo) -> Option<PathBuf> {
+        let name = file.path.file_name()?.to_string_lossy();
+        let modified = file.meta.modified.unwrap_or(file.now);
+        let age = file.now.duration_since(modified).unwrap_or_default();
+
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(&name, file.ext, file.meta.len, age))
+            .find_map(|rule| rule.target.render(file, &name, modified))
+            .or_else(|| self.fallback.as_ref()?.render(file, &name, modified))
+    }
+}
+
+impl Target {
+    fn compile(name: &str, dest: Option<String>, by: Vec<ClassifierSpec>) -> io::Result<Target> {
+        if dest.is_none() && by.is_empty() {
+            return Err(invalid(format!("{}: needs `dest`, `by` or both", name)));
+        }
+        let dest = dest
+            .map(|d| Template::parse(&d, PLACEHOLDERS))
+            .transpose()
+            .map_err(|e| invalid(format!("{}: {}", name, e)))?;
+        let by = if by.is_empty() {
+            None
+        } else {
+            let parts = by
+                .into_iter()
+                .map(|spec| spec.compile())
+                .collect::<io::Result<Vec<_>>>()
+                .map_err(|e| invalid(format!("{}: {}", name, e)))?;
+            Some(Composite::new(parts))
+        };
+        Ok(Target { dest, by })
+    }
+
+    fn render(&self, file: &FileInfo, name: &str, modified: SystemTime) -> Option<PathBuf> {
+        let mut dir = PathBuf::new();
+        if let Some(template) = &self.dest {
+            let (year, month, day) = civil_date(modified);
+            dir.push(template.render(|field| {
+                match field {
+                    "ext" => file.ext.unwrap_or("no_ext").to_string(),
+                    "stem" => file
+                        .path
+                        .file_stem()
+                        .map(|s| s.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                    "name" => name.to_string(),
+                    "year" => year.to_string(),
+                    "month" => month.to_string(),
+                    "day" => day.to_string(),
+                    _ => unreachable!("placeholder validated at parse time"),
+                }
+            }));
+        }
+        if let Some(by) = &self.by {
+            dir.push(by.classify(file)?);
+        }
+        Some(dir)
+    }
+}
+
+impl ClassifierSpec {
+    fn compile(self) -> io::Result<Box<dyn Classifier>> {
+        Ok(match self {
+            ClassifierSpec::Name(name) => match name.as_str() {
+                "extension" => Box::new(ByExtension),
+                "category" => Box::new(Categories::builtin()),
+                "size" => Box::new(SizeBuckets::default()),
+                "date" => Box::new(DateGrouping::new(
+                    dates::DEFAULT_PATTERN,
+                    dates::DEFAULT_SOURCES.to_vec(),
+                )?),
+                _ => {
+                    return Err(invalid(format!(
+                        "unknown classifier {:?} (expected extension, category, size or date)",
+                        name
+                    )));
+                }
+            },
+            ClassifierSpec::Date { date } => {
+                Box::new(DateGrouping::new(&date, dates::DEFAULT_SOURCES.to_vec())?)
+            }
+            ClassifierSpec::Regex { regex, dest } => Box::new(ByRegex::new(&regex, &dest)?),
+        })
+    }
+}
+
+impl Rule {
+    fn compile(index: usize, spec: RuleSpec) -> io::Result<Rule> {
+        let name = spec.name.unwrap_or_else(|| format!("rule #{}", index));
+        let bad = |what: &str, e: &dyn std::fmt::Display| {
+            invalid(format!("{}: invalid {}: {}", name, what, e))
+        };
+
+        let glob = spec
+            .glob
+            .map(|g| glob::Pattern::new(&g).map_err(|e| bad("glob", &e)))
+            .transpose()?;
+        let regex = spec
+            .regex
+            .map(|r| Regex::new(&r).map_err(|e| bad("regex", &e)))
+            .transpose()?;
+        let size = |q: Option<Quantity>| {
+            q.map(|q| parse_size(&q).map_err(|e| bad("size", &e)))
+                .transpose()
+        };
+        let age = |q: Option<Quantity>| {
+            q.map(|q| parse_age(&q).map_err(|e| bad("age", &e)))
+                .transpose()
+        };
+        let target = Target::compile(&name, spec.dest, spec.by)?;
+
+        Ok(Rule {
+            extensions: spec
+                .extensions
+                .iter()
+                .map(|e| e.trim_start_matches('.').to_lowercase())
+                .collect(),
+            glob,
+            regex,
+            mime: spec.mime.map(|m| m.to_lowercase()),
+            min_size: size(spec.min_size)?,
+            max_size: size(spec.max_size)?,
+            min_age: age(spec.min_age)?,
+            max_age: age(spec.max_age)?,
+            target,
+        })
+    }
+
+    fn matches(&self, name: &str, ext: Option<&str>, size: u64, age: Duration) -> bool {
+        if !self.extensions.is_empty()
+            && !ext.is_some_and(|e| self.extensions.iter().any(|x| x == e))
+        {
+            return false;
+        }
+        if self.glob.as_ref().is_some_and(|g| !g.matches(name)) {
+            return false;
+        }
+        if self.regex.as_ref().is_some_and(|r| !r.is_match(name)) {
+            return false;
+        }
+        if self.min_size.is_some_and(|min| size < min)
+            || self.max_size.is_some_and(|max| size > max)
+        {
+            return false;
+        }
+        if self.min_age.is_some_and(|min| age < min) || self.max_age.is_some_and(|max| age > max) {
+            return false;
+        }
+        if let Some(pattern) = &self.mime {
+            let guessed = ext
+                .and_then(|e| mime_guess::from_ext(e).first_raw())
+                .unwrap_or("");
+            return mime_matches(pattern, guessed);
+        }
+        true
+    }
+}
+
+/// `image/*` matches any image type; anything else must match exactly.
+fn mime_matches(pattern: &str, mime: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(top) => mime.split('/').next() == Some(top),
+        None => pattern == mime,
+    }
+}
+
+/// Split `"10M"` into `(10, "m")`.
+fn split_unit(text: &str) -> Result<(u64, String), String> {
+    let text = text.trim();
+    let digits = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let value = text[..digits]
+        .parse()
+        .map_err(|_| format!("{:?} does not start with a number", text))?;
+    Ok((value, text[digits..].trim().to_lowercase()))
+}
+
+/// Parse a size such as `512`, `"100K"` or `"2GiB"` (binary multiples).
+fn parse_size(q: &Quantity) -> Result<u64, String> {
+    let (value, unit) = match q {
+        Quantity::Number(n) => return Ok(*n),
+        Quantity::Text(t) => split_unit(t)?,
+    };
+    let prefix = unit
+        .strip_suffix("ib")
+        .or_else(|| unit.strip_suffix('b'))
+        .unwrap_or(&unit);
+    let shift = match prefix {
+        "" => 0,
+        "k" => 10,
+        "m" => 20,
+        "g" => 30,
+        "t" => 40,
+        _ => return Err(format!("unknown size unit {:?}", unit)),
+    };
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| "size is too large".to_string())
+}
+
+/// Parse an age such as `3600`, `"12h"` or `"30d"`.
+fn parse_age(q: &Quantity) -> Result<Duration, String> {
+    let (value, unit) = match q {
+        Quantity::Number(n) => return Ok(Duration::from_secs(*n)),
+        Quantity::Text(t) => split_unit(t)?,
+    };
+    let secs = match unit.as_str() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        "w" => 7 * 86_400,
+        _ => return Err(format!("unknown age unit {:?}", unit)),
+    };
+    Ok(Duration::from_secs(value.saturating_mul(secs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFs;
+
+    /// 2023-11-14, 22:13:20 UTC.
+    const MODIFIED: u64 = 1_700_000_000;
+    const DAY: Duration = Duration::from_secs(86_400);
+
+    fn load(text: &str) -> RuleSet {
+        let fs = MemoryFs::new();
+        fs.write_file("/rules.toml", text).unwrap();
+        RuleSet::load(&fs, Path::new("/rules.toml")).unwrap()
+    }
+
+    /// Where `rules` put a file called `name` holding `data`, `age` after it
+    /// was last modified.
+    fn classify(rules: &RuleSet, name: &str, data: &str, age: Duration) -> Option<PathBuf> {
+        let fs = MemoryFs::new();
+        let path = Path::new("/in").join(name);
+        fs.write_file(&path, data).unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(MODIFIED);
+        fs.set_modified(&path, modified).unwrap();
+        let meta = fs.metadata(&path).unwrap();
+        let ext = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+        rules.classify(&FileInfo {
+            fs: &fs,
+            path: &path,
+            ext: ext.as_deref(),
+            meta: &meta,
+            now: modified + age,
+        })
+    }
+
+    #[test]
+    fn sizes_take_binary_units() {
+        let size = |t: &str| parse_size(&Quantity::Text(t.to_string()));
+        assert_eq!(parse_size(&Quantity::Number(512)), Ok(512));
+        assert_eq!(size("100"), Ok(100));
+        assert_eq!(size("100K"), Ok(100 << 10));
+        assert_eq!(size(" 1 mb "), Ok(1 << 20));
+        assert_eq!(size("2GiB"), Ok(2 << 30));
+        assert!(size("5x").is_err());
+        assert!(size("MB").is_err());
+        assert!(size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn ages_take_seconds_to_weeks() {
+        let age = |t: &str| parse_age(&Quantity::Text(t.to_string()));
+        assert_eq!(
+            parse_age(&Quantity::Number(60)),
+            Ok(Duration::from_secs(60))
+        );
+        assert_eq!(age("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(age("12h"), Ok(Duration::from_secs(12 * 3600)));
+        assert_eq!(age("30d"), Ok(30 * DAY));
+        assert_eq!(age("2W"), Ok(14 * DAY));
+        assert!(age("1y").is_err());
+    }
+
+    #[test]
+    fn mime_wildcards_match_the_top_level_type() {
+        assert!(mime_matches("image/*", "image/png"));
+        assert!(!mime_matches("image/*", "imagery/png"));
+        assert!(mime_matches("application/pdf", "application/pdf"));
+        assert!(!mime_matches("application/pdf", "application/zip"));
+        assert!(!mime_matches("image/*", ""));
+    }
+
+    #[test]
+    fn the_first_matching_rule_wins_then_the_fallback() {
+        let rules = load(
+            r#"
+            [[rule]]
+            glob = "Screenshot*"
+            extensions = ["png"]
+            dest = "Images/Screenshots/{year}"
+
+            [[rule]]
+            mime = "image/*"
+            dest = "Images/{year}-{month:02}"
+
+            [fallback]
+            dest = "Other/{ext}"
+            "#,
+        );
+        let to = |name: &str| classify(&rules, name, "x", DAY);
+        assert_eq!(
+            to("Screenshot 1.png"),
+            Some("Images/Screenshots/2023".into())
+        );
+        assert_eq!(to("Screenshot 1.gif"), Some("Images/2023-11".into()));
+        assert_eq!(to("cat.png"), Some("Images/2023-11".into()));
+        assert_eq!(to("notes.txt"), Some("Other/txt".into()));
+        assert_eq!(to("Makefile"), Some("Other/no_ext".into()));
+    }
+
+    #[test]
+    fn files_no_rule_matches_stay_without_a_fallback() {
+        let rules = load(
+            r#"
+            [[rule]]
+            min_size = "1K"
+            dest = "Big"
+
+            [[rule]]
+            max_age = "7d"
+            dest = "Recent"
+            "#,
+        );
+        let big = "x".repeat(2048);
+        assert_eq!(
+            classify(&rules, "a.bin", &big, 30 * DAY),
+            Some("Big".into())
+        );
+        assert_eq!(classify(&rules, "a.bin", "x", DAY), Some("Recent".into()));
+        assert_eq!(classify(&rules, "a.bin", "x", 30 * DAY), None);
+    }
+
+    #[test]
+    fn a_rule_whose_classifiers_pass_is_passed_over() {
+        let rules = load(
+            r#"
+            [[rule]]
+            by = [{ regex = "^invoice-(\\d{4})", dest = "Invoices/$1" }]
+
+            [[rule]]
+            dest = "Docs"
+            by = ["extension"]
+            "#,
+        );
+        assert_eq!(
+            classify(&rules, "invoice-2024-03.pdf", "x", DAY),
+            Some("Invoices/2024".into())
+        );
+        assert_eq!(
+            classify(&rules, "report.pdf", "x", DAY),
+            Some("Docs/pdf".into())
+        );
+    }
+
+    #[test]
+    fn a_target_needs_a_dest_or_classifiers() {
+        let fs = MemoryFs::new();
+        fs.write_file("/rules.toml", "[[rule]]\nname = \"empty\"\nglob = \"*\"\n")
+            .unwrap();
+        let err = RuleSet::load(&fs, Path::new("/rules.toml")).err().unwrap();
+        assert!(err.to_string().contains("empty: needs"), "{}", err);
+    }
+}
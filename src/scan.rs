@@ -0,0 +1,463 @@
+// Walking a folder and summarising what's in it.
+//
+// `Scanner` is an iterator over the entries under a folder, depth first, in
+// the order `read_dir` returns them. `scan` folds those entries into a
+// `Report` for the `scan` subcommand.
+//
+// Symlinks are reported as links unless the walk follows them. A followed
+// link to a directory is descended into, except when it leads back to a
+// directory the walk is already inside, which would loop forever.
+
+use crate::failure::{self, Failure};
+use crate::filter::{self, Filter, Patterns};
+use crate::sniff::{self, ClassifyBy};
+use crate::vfs::{FileSystem, Metadata};
+use clap::ValueEnum;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::vec;
+
+#[derive(Clone, Copy, Debug)]
+pub struct WalkOptions {
+    /// Entries directly inside the walked folder are at depth 1.
+    pub min_depth: usize,
+    pub max_depth: usize,
+    /// Look through symlinks to what they point at.
+    pub follow_links: bool,
+}
+
+/// What to do with symlinks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Symlinks {
+    /// Leave them where they are; `scan` only counts them
+    #[default]
+    Skip,
+    /// Treat each like what it points to, descending into linked directories
+    Follow,
+    /// Move the links themselves, broken ones included (`organize` only)
+    MoveLink,
+}
+
+/// Whether an entry is a symlink, and if so whether it leads anywhere.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkStatus {
+    NotLink,
+    Live,
+    /// Its target is missing or can't be reached, e.g. a link loop.
+    Broken,
+}
+
+impl WalkOptions {
+    /// Only the entries directly inside the folder.
+    pub fn top_level() -> WalkOptions {
+        WalkOptions {
+            min_depth: 1,
+            max_depth: 1,
+            follow_links: false,
+        }
+    }
+
+    /// Everything under the folder, however deep.
+    pub fn recursive() -> WalkOptions {
+        WalkOptions {
+            min_depth: 1,
+            max_depth: usize::MAX,
+            follow_links: false,
+        }
+    }
+}
+
+/// An entry found by `Scanner`.
+#[derive(Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    /// Directory containing the entry, relative to the walked folder.
+    pub rel_dir: PathBuf,
+    pub depth: usize,
+    /// Metadata of the entry, or of its target for a live symlink when the
+    /// walk follows links.
+    pub meta: Metadata,
+    pub link: LinkStatus,
+}
+
+/// Iterator over the entries under a folder within the configured depths.
+/// Directories are yielded before their contents.
+pub struct Scanner<'a> {
+    fs: &'a dyn FileSystem,
+    opts: WalkOptions,
+    filter: Option<&'a Filter>,
+    /// Real paths of directories to leave out, contents and all.
+    pruned: Vec<PathBuf>,
+    /// Open directories, innermost last.
+    stack: Vec<Level>,
+    /// Directory to list before reading further, so it is only read once
+    /// its own entry has been yielded.
+    pending: Option<(PathBuf, PathBuf, usize)>,
+}
+
+/// A directory being listed.
+struct Level {
+    listing: vec::IntoIter<PathBuf>,
+    /// Path relative to the walked folder.
+    rel_dir: PathBuf,
+    /// Depth of the entries in it.
+    depth: usize,
+    /// Patterns from its ignore files, if any.
+    ignores: Option<Patterns>,
+    /// Its real path, when following links, to spot loops.
+    real: Option<PathBuf>,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new(
+        fs: &'a dyn FileSystem,
+        folder: &Path,
+        opts: &WalkOptions,
+    ) -> io::Result<Scanner<'a>> {
+        Scanner::build(fs, folder, opts, None)
+    }
+
+    /// Like `new`, but leaving out what `filter` excludes. Excluded
+    /// directories are not descended into.
+    pub fn with_filter(
+        fs: &'a dyn FileSystem,
+        folder: &Path,
+        opts: &WalkOptions,
+        filter: &'a Filter,
+    ) -> io::Result<Scanner<'a>> {
+        Scanner::build(fs, folder, opts, Some(filter))
+    }
+
+    /// Leave out the directory whose real path is `real`, and everything
+    /// under it.
+    pub fn pruning(mut self, real: PathBuf) -> Scanner<'a> {
+        self.pruned.push(real);
+        self
+    }
+
+    fn build(
+        fs: &'a dyn FileSystem,
+        folder: &Path,
+        opts: &WalkOptions,
+        filter: Option<&'a Filter>,
+    ) -> io::Result<Scanner<'a>> {
+        let mut scanner = Scanner {
+            fs,
+            opts: *opts,
+            filter,
+            pruned: Vec::new(),
+            stack: Vec::new(),
+            pending: None,
+        };
+        scanner.open(folder, PathBuf::from("."), 1)?;
+        Ok(scanner)
+    }
+
+    fn open(&mut self, dir: &Path, rel_dir: PathBuf, depth: usize) -> io::Result<()> {
+        let listing = self
+            .fs
+            .read_dir(dir)
+            .map_err(failure::at(dir, "read_dir"))?
+            .into_iter();
+        let ignores = match self.filter {
+            Some(filter) => filter
+                .ignores_in(self.fs, dir, relative(&rel_dir))
+                .map_err(failure::at(dir, "read ignores"))?,
+            None => None,
+        };
+        let real = if self.opts.follow_links {
+            Some(
+                self.fs
+                    .canonicalize(dir)
+                    .map_err(failure::at(dir, "canonicalize"))?,
+            )
+        } else {
+            None
+        };
+        self.stack.push(Level {
+            listing,
+            rel_dir,
+            depth,
+            ignores,
+            real,
+        });
+        Ok(())
+    }
+
+    fn visit(&mut self, path: PathBuf, rel_dir: &Path, depth: usize) -> io::Result<Option<Entry>> {
+        let (meta, link) = link_metadata(self.fs, &path, self.opts.follow_links)
+            .map_err(failure::at(&path, "metadata"))?;
+
+        if let Some(filter) = self.filter {
+            let rel = relative(rel_dir).join(path.file_name().unwrap_or_default());
+            let ignores = self.stack.iter().filter_map(|level| level.ignores.as_ref());
+            if filter.excludes(&rel, meta.is_dir(), ignores) {
+                return Ok(None);
+            }
+        }
+
+        if meta.is_dir()
+            && !self.pruned.is_empty()
+            && self
+                .fs
+                .canonicalize(&path)
+                .is_ok_and(|real| self.pruned.contains(&real))
+        {
+            return Ok(None);
+        }
+
+        // `meta` is only a directory for a link when following links.
+        if meta.is_dir()
+            && depth < self.opts.max_depth
+            && (link == LinkStatus::NotLink || !self.leads_back(&path)?)
+        {
+            let rel = rel_dir.join(path.file_name().unwrap_or_default());
+            self.pending = Some((path.clone(), rel, depth + 1));
+        }
+
+        Ok((depth >= self.opts.min_depth).then(|| Entry {
+            path,
+            rel_dir: rel_dir.to_path_buf(),
+            depth,
+            meta,
+            link,
+        }))
+    }
+
+    /// Whether the directory `path` leads to is one the walk is inside.
+    fn leads_back(&self, path: &Path) -> io::Result<bool> {
+        let real = self
+            .fs
+            .canonicalize(path)
+            .map_err(failure::at(path, "canonicalize"))?;
+        Ok(self
+            .stack
+            .iter()
+            .any(|level| level.real.as_ref() == Some(&real)))
+    }
+}
+
+/// Metadata of `path` and whether it is a symlink. A live link's metadata is
+/// its target's when `follow_links` is set, and its own otherwise.
+pub fn link_metadata(
+    fs: &dyn FileSystem,
+    path: &Path,
+    follow_links: bool,
+) -> io::Result<(Metadata, LinkStatus)> {
+    let own = fs.symlink_metadata(path)?;
+    if !own.is_symlink() {
+        return Ok((own, LinkStatus::NotLink));
+    }
+    Ok(match fs.metadata(path) {
+        Ok(target) if follow_links => (target, LinkStatus::Live),
+        Ok(_) => (own, LinkStatus::Live),
+        Err(_) => (own, LinkStatus::Broken),
+    })
+}
+
+/// `rel_dir` without its leading `./`, as filter patterns see it.
+fn relative(rel_dir: &Path) -> &Path {
+    rel_dir.strip_prefix(".").unwrap_or(rel_dir)
+}
+
+impl Iterator for Scanner<'_> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<io::Result<Entry>> {
+        loop {
+            if let Some((dir, rel_dir, depth)) = self.pending.take()
+                && let Err(e) = self.open(&dir, rel_dir, depth)
+            {
+                return Some(Err(e));
+            }
+
+            let level = self.stack.last_mut()?;
+            let Some(path) = level.listing.next() else {
+                self.stack.pop();
+                continue;
+            };
+            let (rel_dir, depth) = (level.rel_dir.clone(), level.depth);
+            match self.visit(path, &rel_dir, depth) {
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+pub struct ScanOptions {
+    pub walk: WalkOptions,
+    pub filter: Filter,
+    /// How many of the largest files to keep in the report.
+    pub top: usize,
+    pub classify_by: ClassifyBy,
+    /// Note entries that can't be read in `Report::failures` and carry on.
+    pub keep_going: bool,
+}
+
+/// Upper bounds (exclusive) of the size histogram buckets; the last bucket
+/// holds everything at or above the final bound.
+pub const SIZE_BUCKET_BOUNDS: [u64; 3] = [1 << 10, 1 << 20, 100 << 20];
+pub const SIZE_BUCKET_LABELS: [&str; 4] = ["<1K", "<1M", "<100M", ">=100M"];
+
+pub struct Report {
+    pub total_entries: usize,
+    pub files: usize,
+    pub dirs: usize,
+    /// Dotfiles and files inside dot-directories, included in `files`.
+    pub hidden_files: usize,
+    /// Symlinks seen, whether or not they were followed.
+    pub symlinks: usize,
+    /// Symlinks whose target is missing, included in `symlinks`.
+    pub broken_symlinks: usize,
+    pub total_bytes: u64,
+    pub by_extension: BTreeMap<String, ExtTotals>,
+    /// Subtotals keyed by directory, relative to the scanned folder.
+    pub by_dir: BTreeMap<PathBuf, DirTotals>,
+    /// Largest files, biggest first.
+    pub largest: Vec<(PathBuf, u64)>,
+    pub size_histogram: [usize; 4],
+    /// Files whose extension contradicts their detected content. Only
+    /// filled in when classifying by content.
+    pub mismatches: Vec<Mismatch>,
+    /// Entries skipped because they couldn't be read, with `keep_going`.
+    pub failures: Vec<Failure>,
+}
+
+pub struct Mismatch {
+    pub path: PathBuf,
+    pub extension: String,
+    pub content: &'static str,
+}
+
+#[derive(Default)]
+pub struct ExtTotals {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+pub struct DirTotals {
+    pub files: usize,
+    pub dirs: usize,
+    pub bytes: u64,
+}
+
+/// Summarise the entries under `folder`.
+pub fn scan(fs: &dyn FileSystem, folder: &Path, opts: &ScanOptions) -> io::Result<Report> {
+    let mut report = Report {
+        total_entries: 0,
+        files: 0,
+        dirs: 0,
+        hidden_files: 0,
+        symlinks: 0,
+        broken_symlinks: 0,
+        total_bytes: 0,
+        by_extension: BTreeMap::new(),
+        by_dir: BTreeMap::new(),
+        largest: Vec::new(),
+        size_histogram: [0; 4],
+        mismatches: Vec::new(),
+        failures: Vec::new(),
+    };
+
+    // Min-heap of the biggest files seen so far, capped at `opts.top`.
+    let mut largest = BinaryHeap::new();
+
+    for entry in Scanner::with_filter(fs, folder, &opts.walk, &opts.filter)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if opts.keep_going && Failure::of(&e).is_some() => {
+                report
+                    .failures
+                    .push(Failure::from_error(&e, folder, "scan"));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        report.total_entries += 1;
+        if entry.link != LinkStatus::NotLink {
+            report.symlinks += 1;
+        }
+        if entry.link == LinkStatus::Broken {
+            report.broken_symlinks += 1;
+        }
+
+        if entry.meta.is_dir() {
+            report.dirs += 1;
+            report.by_dir.entry(entry.rel_dir).or_default().dirs += 1;
+            continue;
+        }
+        if !entry.meta.is_file() {
+            continue;
+        }
+
+        let size = entry.meta.len;
+        report.files += 1;
+        report.total_bytes += size;
+        if filter::is_hidden(
+            &entry
+                .rel_dir
+                .join(entry.path.file_name().unwrap_or_default()),
+        ) {
+            report.hidden_files += 1;
+        }
+
+        let dir_totals = report.by_dir.entry(entry.rel_dir).or_default();
+        dir_totals.files += 1;
+        dir_totals.bytes += size;
+
+        let name_ext = entry
+            .path
+            .extension()
+            .and_then(|s| s.to_str())
+            .map(|s| s.to_lowercase());
+        let ext = if opts.classify_by == ClassifyBy::Extension {
+            name_ext
+        } else {
+            let sniffed = sniff::sniff(fs, &entry.path);
+            if let (Some(ext), Some(content)) = (&name_ext, sniffed)
+                && !sniff::ext_matches_content(ext, content)
+            {
+                report.mismatches.push(Mismatch {
+                    path: entry.path.clone(),
+                    extension: ext.clone(),
+                    content,
+                });
+            }
+            match opts.classify_by {
+                ClassifyBy::Content => sniffed.map(String::from),
+                _ => sniffed.map(String::from).or(name_ext),
+            }
+        };
+        let ext = ext.unwrap_or_else(|| "(no_ext)".to_string());
+
+        let ext_totals = report.by_extension.entry(ext).or_default();
+        ext_totals.files += 1;
+        ext_totals.bytes += size;
+
+        let bucket = SIZE_BUCKET_BOUNDS
+            .iter()
+            .position(|&bound| size < bound)
+            .unwrap_or(SIZE_BUCKET_BOUNDS.len());
+        report.size_histogram[bucket] += 1;
+
+        if opts.top > 0 {
+            largest.push(Reverse((size, entry.path)));
+            if largest.len() > opts.top {
+                largest.pop();
+            }
+        }
+    }
+
+    report.largest = largest
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((size, path))| (path, size))
+        .collect();
+
+    Ok(report)
+}
@@ -0,0 +1,319 @@
+// Content-based file type detection from magic numbers.
+//
+// Only the first few KiB are read. The result is the conventional extension
+// for the detected format, so it can stand in anywhere a file extension is
+// used (scan totals, organize folders, categories, rules).
+
+use crate::vfs::FileSystem;
+use clap::ValueEnum;
+use std::io::Read;
+use std::path::Path;
+
+const SNIFF_LEN: u64 = 8 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ClassifyBy {
+    /// Trust the file name's extension
+    #[default]
+    Extension,
+    /// Use the detected content type; unrecognised files count as having no extension
+    Content,
+    /// Use the detected content type, falling back to the extension
+    ContentThenExtension,
+}
+
+/// The extension to classify `path` by, lowercased.
+pub fn effective_ext(fs: &dyn FileSystem, path: &Path, classify_by: ClassifyBy) -> Option<String> {
+    let from_name = || {
+        path.extension()
+            .and_then(|s| s.to_str())
+            .map(|s| s.to_lowercase())
+    };
+    match classify_by {
+        ClassifyBy::Extension => from_name(),
+        ClassifyBy::Content => sniff(fs, path).map(String::from),
+        ClassifyBy::ContentThenExtension => sniff(fs, path).map(String::from).or_else(from_name),
+    }
+}
+
+/// Detect the format of `path` from its leading bytes. Unreadable files and
+/// unknown formats give `None`.
+pub fn sniff(fs: &dyn FileSystem, path: &Path) -> Option<&'static str> {
+    let mut head = Vec::new();
+    fs.open(path)
+        .ok()?
+        .take(SNIFF_LEN)
+        .read_to_end(&mut head)
+        .ok()?;
+    sniff_bytes(&head)
+}
+
+fn sniff_bytes(b: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| b.get(offset..offset + magic.len()) == Some(magic);
+
+    let kind = if at(0, b"\x89PNG\r\n\x1a\n") {
+        "png"
+    } else if at(0, b"\xff\xd8\xff") {
+        "jpg"
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        "gif"
+    } else if at(0, b"II*\0") || at(0, b"MM\0*") {
+        "tiff"
+    } else if at(0, b"BM") && at(6, b"\0\0\0\0") {
+        "bmp"
+    } else if at(0, b"8BPS") {
+        "psd"
+    } else if at(0, b"%PDF-") {
+        "pdf"
+    } else if at(0, b"PK\x03\x04") {
+        zip_flavour(b)
+    } else if at(0, b"\x7fELF") {
+        "elf"
+    } else if at(0, b"MZ") {
+        "exe"
+    } else if at(0, b"\0asm") {
+        "wasm"
+    } else if at(0, b"\x1f\x8b") {
+        "gz"
+    } else if at(0, b"BZh") {
+        "bz2"
+    } else if at(0, b"\xfd7zXZ\0") {
+        "xz"
+    } else if at(0, b"\x28\xb5\x2f\xfd") {
+        "zst"
+    } else if at(0, b"7z\xbc\xaf\x27\x1c") {
+        "7z"
+    } else if at(0, b"Rar!\x1a\x07") {
+        "rar"
+    } else if at(257, b"ustar") {
+        "tar"
+    } else if at(0, b"SQLite format 3\0") {
+        "sqlite"
+    } else if at(0, b"ID3") || is_mpeg_audio_frame(b) {
+        "mp3"
+    } else if at(0, b"fLaC") {
+        "flac"
+    } else if at(0, b"OggS") {
+        "ogg"
+    } else if at(0, b"RIFF") && at(8, b"WAVE") {
+        "wav"
+    } else if at(0, b"RIFF") && at(8, b"AVI ") {
+        "avi"
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        "webp"
+    } else if at(4, b"ftyp") {
+        iso_media_flavour(b.get(8..12)?)
+    } else if at(0, b"\x1a\x45\xdf\xa3") {
+        // Matroska; the EBML header names the doc type early on.
+        if contains(&b[..b.len().min(64)], b"webm") {
+            "webm"
+        } else {
+            "mkv"
+        }
+    } else {
+        return None;
+    };
+    Some(kind)
+}
+
+/// MPEG-1/2 Layer III frame header without an ID3 tag in front.
+fn is_mpeg_audio_frame(b: &[u8]) -> bool {
+    matches!(b, [0xff, second, ..] if second & 0xe0 == 0xe0 && second & 0x06 == 0x02)
+}
+
+/// ZIP-based formats identify themselves by the names of their entries,
+/// which for all common ones appear in the first local headers.
+fn zip_flavour(head: &[u8]) -> &'static str {
+    if contains(head, b"mimetypeapplication/epub+zip") {
+        "epub"
+    } else if contains(head, b"mimetypeapplication/vnd.oasis.opendocument.text") {
+        "odt"
+    } else if contains(
+        head,
+        b"mimetypeapplication/vnd.oasis.opendocument.spreadsheet",
+    ) {
+        "ods"
+    } else if contains(
+        head,
+        b"mimetypeapplication/vnd.oasis.opendocument.presentation",
+    ) {
+        "odp"
+    } else if contains(head, b"[Content_Types].xml") || contains(head, b"_rels/.rels") {
+        if contains(head, b"word/") {
+            "docx"
+        } else if contains(head, b"xl/") {
+            "xlsx"
+        } else if contains(head, b"ppt/") {
+            "pptx"
+        } else {
+            "zip"
+        }
+    } else if contains(head, b"AndroidManifest.xml") {
+        "apk"
+    } else if contains(head, b"META-INF/MANIFEST.MF") {
+        "jar"
+    } else {
+        "zip"
+    }
+}
+
+/// ISO base media files (MP4 and friends) carry a brand after `ftyp`.
+fn iso_media_flavour(brand: &[u8]) -> &'static str {
+    match brand {
+        b"qt  " => "mov",
+        b"M4A " | b"M4B " => "m4a",
+        b"M4V " => "m4v",
+        b"heic" | b"heix" | b"mif1" | b"msf1" => "heic",
+        b"avif" | b"avis" => "avif",
+        [b'3', b'g', ..] => "3gp",
+        _ => "mp4",
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Extensions that are legitimately used for a detected format, beyond the
+/// canonical one `sniff` returns.
+const COMPATIBLE: &[(&str, &[&str])] = &[
+    ("jpg", &["jpeg", "jpe", "jfif"]),
+    ("tiff", &["tif", "dng", "cr2", "nef", "arw", "orf", "rw2"]),
+    ("gz", &["tgz", "gzip"]),
+    ("bz2", &["tbz", "tbz2"]),
+    ("xz", &["txz"]),
+    ("zst", &["zstd", "tzst"]),
+    (
+        "zip",
+        &[
+            "jar", "apk", "epub", "docx", "xlsx", "pptx", "odt", "ods", "odp", "xpi", "whl", "aar",
+            "ipa", "kmz", "cbz", "nupkg", "vsix",
+        ],
+    ),
+    ("jar", &["war", "ear"]),
+    ("docx", &["docm", "dotx"]),
+    ("xlsx", &["xlsm", "xltx"]),
+    ("pptx", &["pptm", "potx"]),
+    ("elf", &["so", "o", "ko", "bin", "out", "axf"]),
+    ("exe", &["dll", "sys", "scr", "efi", "com"]),
+    ("sqlite", &["sqlite3", "db", "db3"]),
+    ("mp4", &["m4v", "m4a", "m4b", "m4p", "f4v"]),
+    ("m4a", &["m4b", "mp4"]),
+    ("m4v", &["mp4"]),
+    ("mov", &["qt"]),
+    ("heic", &["heif", "hif"]),
+    ("mkv", &["mka", "mks", "mk3d"]),
+    ("webm", &["mkv"]),
+    ("ogg", &["oga", "ogv", "opus", "spx", "ogx"]),
+    ("mp3", &["mpga"]),
+];
+
+/// Whether a file named `*.ext` may hold content detected as `sniffed`.
+pub fn ext_matches_content(ext: &str, sniffed: &str) -> bool {
+    let compatible = |kind: &str, ext: &str| {
+        COMPATIBLE
+            .iter()
+            .any(|(k, exts)| *k == kind && exts.contains(&ext))
+    };
+    // A ZIP-based document is still a perfectly good `.zip`.
+    ext == sniffed || compatible(sniffed, ext) || (ext == "zip" && compatible("zip", sniffed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFs;
+
+    /// `magic` at `offset`, padded with zeros.
+    fn head(offset: usize, magic: &[u8]) -> Vec<u8> {
+        let mut b = vec![0; offset];
+        b.extend_from_slice(magic);
+        b.resize(b.len().max(512), 0);
+        b
+    }
+
+    #[test]
+    fn formats_are_told_by_their_magic() {
+        assert_eq!(sniff_bytes(b"\x89PNG\r\n\x1a\n...."), Some("png"));
+        assert_eq!(sniff_bytes(b"\xff\xd8\xff\xe0"), Some("jpg"));
+        assert_eq!(sniff_bytes(b"%PDF-1.7"), Some("pdf"));
+        assert_eq!(sniff_bytes(&head(257, b"ustar")), Some("tar"));
+        assert_eq!(sniff_bytes(b"ID3\x04"), Some("mp3"));
+        assert_eq!(sniff_bytes(b"\xff\xfb\x90\x00"), Some("mp3"));
+        assert_eq!(sniff_bytes(b"RIFF\0\0\0\0WEBPVP8 "), Some("webp"));
+        assert_eq!(sniff_bytes(b"RIFF\0\0\0\0WAVEfmt "), Some("wav"));
+        assert_eq!(sniff_bytes(b"just some text"), None);
+        assert_eq!(sniff_bytes(b""), None);
+    }
+
+    #[test]
+    fn containers_are_told_apart_by_what_they_hold() {
+        let zip = |entries: &[u8]| {
+            let mut b = b"PK\x03\x04".to_vec();
+            b.extend_from_slice(entries);
+            sniff_bytes(&b)
+        };
+        assert_eq!(zip(b"mimetypeapplication/epub+zip"), Some("epub"));
+        assert_eq!(
+            zip(b"[Content_Types].xml...word/document.xml"),
+            Some("docx")
+        );
+        assert_eq!(zip(b"[Content_Types].xml...xl/workbook.xml"), Some("xlsx"));
+        assert_eq!(zip(b"META-INF/MANIFEST.MF"), Some("jar"));
+        assert_eq!(zip(b"photos/a.jpg"), Some("zip"));
+
+        let ftyp = |brand: &[u8]| sniff_bytes(&[b"\0\0\0\x18ftyp", brand].concat());
+        assert_eq!(ftyp(b"isom"), Some("mp4"));
+        assert_eq!(ftyp(b"qt  "), Some("mov"));
+        assert_eq!(ftyp(b"heic"), Some("heic"));
+        assert_eq!(ftyp(b"3gp5"), Some("3gp"));
+        assert_eq!(sniff_bytes(b"\0\0\0\x18ftyp"), None, "no room for a brand");
+
+        assert_eq!(
+            sniff_bytes(b"\x1a\x45\xdf\xa3\x42\x82\x84webm"),
+            Some("webm")
+        );
+        assert_eq!(
+            sniff_bytes(b"\x1a\x45\xdf\xa3\x42\x82\x88matroska"),
+            Some("mkv")
+        );
+    }
+
+    #[test]
+    fn extensions_may_differ_from_the_canonical_one() {
+        assert!(ext_matches_content("png", "png"));
+        assert!(ext_matches_content("jpeg", "jpg"));
+        assert!(ext_matches_content("cr2", "tiff"));
+        assert!(
+            ext_matches_content("epub", "zip"),
+            "detected as a plain zip"
+        );
+        assert!(ext_matches_content("zip", "docx"), "a docx is still a zip");
+        assert!(!ext_matches_content("docx", "xlsx"));
+        assert!(!ext_matches_content("png", "jpg"));
+        assert!(!ext_matches_content("txt", "elf"));
+    }
+
+    #[test]
+    fn content_overrides_a_misleading_name() {
+        let fs = MemoryFs::new();
+        fs.write_file("/photo.txt", b"\x89PNG\r\n\x1a\n".to_vec())
+            .unwrap();
+        fs.write_file("/notes.TXT", "plain").unwrap();
+        let ext = |path: &str, by| effective_ext(&fs, Path::new(path), by);
+
+        assert_eq!(
+            ext("/photo.txt", ClassifyBy::Extension).as_deref(),
+            Some("txt")
+        );
+        assert_eq!(
+            ext("/photo.txt", ClassifyBy::Content).as_deref(),
+            Some("png")
+        );
+        assert_eq!(ext("/notes.TXT", ClassifyBy::Content), None);
+        assert_eq!(
+            ext("/notes.TXT", ClassifyBy::ContentThenExtension).as_deref(),
+            Some("txt")
+        );
+    }
+}
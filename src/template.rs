@@ -0,0 +1,163 @@
+// Destination templates such as `Photos/{year}/{month:02}`.
+//
+// A template is parsed once, up front, so typos in placeholder names are
+// reported before any file is touched. Rendering is just string substitution;
+// callers decide which placeholders exist and what they expand to.
+
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    /// `{name}` or `{name:0N}` (zero-padded to width N).
+    Field {
+        name: String,
+        width: usize,
+    },
+}
+
+impl Template {
+    /// Parse `source`, accepting only the placeholder names in `allowed`.
+    pub fn parse(source: &str, allowed: &[&str]) -> io::Result<Template> {
+        let invalid = |msg: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("template {:?}: {}", source, msg),
+            )
+        };
+
+        let path = Path::new(source);
+        if !path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(invalid("must be a relative path without `..`".into()));
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = source;
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                parts.push(Part::Literal(rest[..open].to_string()));
+            }
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| invalid("unclosed `{`".into()))?
+                + open;
+            let inner = &rest[open + 1..close];
+
+            let (name, width) = match inner.split_once(':') {
+                None => (inner, 0),
+                Some((name, spec)) => {
+                    let width = spec
+                        .strip_prefix('0')
+                        .and_then(|w| w.parse().ok())
+                        .ok_or_else(|| {
+                            invalid(format!("unsupported format `{}`, expected `0N`", spec))
+                        })?;
+                    (name, width)
+                }
+            };
+            if !allowed.contains(&name) {
+                return Err(invalid(format!(
+                    "unknown placeholder `{{{}}}` (expected one of: {})",
+                    name,
+                    allowed.join(", ")
+                )));
+            }
+            parts.push(Part::Field {
+                name: name.to_string(),
+                width,
+            });
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        Ok(Template { parts })
+    }
+
+    /// Expand the template; `lookup` supplies the value for each placeholder.
+    pub fn render(&self, lookup: impl Fn(&str) -> String) -> PathBuf {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => out.push_str(text),
+                Part::Field { name, width } => {
+                    out.push_str(&format!("{:0>width$}", lookup(name), width = *width))
+                }
+            }
+        }
+        PathBuf::from(out)
+    }
+}
+
+/// Calendar date (UTC) of a timestamp, as `(year, month, day)`.
+pub fn civil_date(time: SystemTime) -> (i64, u32, u32) {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    };
+    let days = secs.div_euclid(86_400);
+
+    // Howard Hinnant's days-to-civil algorithm.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn date(secs: i64) -> (i64, u32, u32) {
+        let time = if secs >= 0 {
+            UNIX_EPOCH + Duration::from_secs(secs as u64)
+        } else {
+            UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+        };
+        civil_date(time)
+    }
+
+    #[test]
+    fn civil_dates_around_the_epoch() {
+        assert_eq!(date(0), (1970, 1, 1));
+        assert_eq!(date(86_399), (1970, 1, 1));
+        assert_eq!(date(86_400), (1970, 1, 2));
+        assert_eq!(date(-1), (1969, 12, 31));
+        assert_eq!(date(-86_400), (1969, 12, 31));
+        assert_eq!(date(-86_401), (1969, 12, 30));
+    }
+
+    #[test]
+    fn civil_dates_across_leap_days() {
+        // 2000 is a leap year (divisible by 400), 1900 and 2100 are not.
+        assert_eq!(date(951_782_400), (2000, 2, 29));
+        assert_eq!(date(951_868_800), (2000, 3, 1));
+        assert_eq!(date(1_709_164_800), (2024, 2, 29));
+        assert_eq!(date(1_740_700_800), (2025, 2, 28));
+        assert_eq!(date(1_740_787_200), (2025, 3, 1));
+        assert_eq!(date(4_107_456_000), (2100, 2, 28));
+        assert_eq!(date(4_107_542_400), (2100, 3, 1));
+        assert_eq!(date(-2_203_977_600), (1900, 2, 28));
+        assert_eq!(date(-2_203_891_200), (1900, 3, 1));
+        assert_eq!(date(1_703_980_800 + 86_399), (2023, 12, 31));
+    }
+}
@@ -0,0 +1,914 @@
+// Filesystem access, behind a trait.
+//
+// Everything the scanner, planner, executor, journal and duplicate finder do
+// to files goes through `FileSystem`. `RealFs` is `std::fs`. `MemoryFs` is an
+// in-memory tree with mount points and injectable faults, so scenarios such
+// as permission errors or moves across filesystems can be run
+// deterministically without touching disk.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ffi::OsString;
+use std::fs::{self, File, FileTimes, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    /// Sockets, devices, FIFOs.
+    Other,
+}
+
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    pub kind: FileKind,
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    pub accessed: Option<SystemTime>,
+    /// Birth time, where the platform records it.
+    pub created: Option<SystemTime>,
+    /// `(device, inode)` where the platform has them; hard links share one.
+    pub file_id: Option<(u64, u64)>,
+}
+
+impl Metadata {
+    pub fn is_file(&self) -> bool {
+        self.kind == FileKind::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Dir
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind == FileKind::Symlink
+    }
+}
+
+impl From<fs::Metadata> for Metadata {
+    fn from(meta: fs::Metadata) -> Metadata {
+        let file_type = meta.file_type();
+        let kind = if file_type.is_symlink() {
+            FileKind::Symlink
+        } else if file_type.is_dir() {
+            FileKind::Dir
+        } else if file_type.is_file() {
+            FileKind::File
+        } else {
+            FileKind::Other
+        };
+        #[cfg(unix)]
+        let file_id = {
+            use std::os::unix::fs::MetadataExt;
+            Some((meta.dev(), meta.ino()))
+        };
+        #[cfg(not(unix))]
+        let file_id = None;
+
+        Metadata {
+            kind,
+            len: meta.len(),
+            modified: meta.modified().ok(),
+            accessed: meta.accessed().ok(),
+            created: meta.created().ok(),
+            file_id,
+        }
+    }
+}
+
+/// A readable, seekable open file.
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+pub trait FileSystem {
+    /// Metadata with symlinks followed.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Metadata of `path` itself, even if it is a symlink.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Paths of the entries in a directory, each `path` joined with a name.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Where the symlink at `path` points, as stored in the link.
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadSeek>>;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Rename within one filesystem, replacing a file at `to`. Fails with
+    /// `ErrorKind::CrossesDevices` when `from` and `to` are on different ones.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove an empty directory.
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Copy `src` to `dst`, which must not exist yet, with its permissions
+    /// and timestamps, and sync the copy to disk.
+    fn copy_file(&self, src: &Path, dst: &Path) -> io::Result<()>;
+
+    /// Like `copy_file`, but the copy shares `src`'s storage until either is
+    /// written to. Fails with `ErrorKind::Unsupported` where the filesystem
+    /// can't do that and `CrossesDevices` between filesystems.
+    fn reflink(&self, src: &Path, dst: &Path) -> io::Result<()>;
+
+    /// Append `data` to `path`, creating it if needed, and sync it to disk.
+    fn append(&self, path: &Path, data: &[u8]) -> io::Result<()>;
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()>;
+
+    /// Create a symlink at `link` pointing to the file `target`.
+    fn symlink_file(&self, target: &Path, link: &Path) -> io::Result<()>;
+}
+
+/// The real filesystem, via `std::fs`.
+pub struct RealFs;
+
+impl FileSystem for RealFs {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::metadata(path).map(Metadata::from)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(path).map(Metadata::from)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect()
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadSeek>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn copy_file(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let mut reader = File::open(src)?;
+        let meta = reader.metadata()?;
+        let mut writer = OpenOptions::new().write(true).create_new(true).open(dst)?;
+
+        io::copy(&mut reader, &mut writer)?;
+        writer.set_permissions(meta.permissions())?;
+        writer.set_times(
+            FileTimes::new()
+                .set_accessed(meta.accessed()?)
+                .set_modified(meta.modified()?),
+        )?;
+        writer.sync_all()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn reflink(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let reader = File::open(src)?;
+        let meta = reader.metadata()?;
+        let writer = OpenOptions::new().write(true).create_new(true).open(dst)?;
+
+        // SAFETY: both descriptors are open for the duration of the call.
+        let cloned = unsafe { libc::ioctl(writer.as_raw_fd(), libc::FICLONE, reader.as_raw_fd()) };
+        let result = if cloned == 0 {
+            writer.set_permissions(meta.permissions()).and_then(|()| {
+                writer.set_times(
+                    FileTimes::new()
+                        .set_accessed(meta.accessed()?)
+                        .set_modified(meta.modified()?),
+                )
+            })
+        } else {
+            let error = io::Error::last_os_error();
+            Err(match error.raw_os_error() {
+                // Filesystems without clones answer with any of these.
+                Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EINVAL | libc::ENOSYS) => {
+                    io::Error::new(io::ErrorKind::Unsupported, error)
+                }
+                _ => error,
+            })
+        };
+        if result.is_err() {
+            let _ = fs::remove_file(dst);
+        }
+        result
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn reflink(&self, _src: &Path, _dst: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn append(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(data)?;
+        file.sync_data()
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        fs::hard_link(original, link)
+    }
+
+    fn symlink_file(&self, target: &Path, link: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        return std::os::unix::fs::symlink(target, link);
+        #[cfg(windows)]
+        return std::os::windows::fs::symlink_file(target, link);
+    }
+}
+
+/// Operations a `MemoryFs` fault can be attached to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// Also covers reading where a symlink points.
+    Metadata,
+    ReadDir,
+    Canonicalize,
+    Open,
+    CreateDir,
+    /// Checked against both the source and the destination.
+    Rename,
+    RemoveFile,
+    RemoveDir,
+    /// Copies and reflinks, checked against both the source and the
+    /// destination.
+    Copy,
+    Append,
+    /// Hard and symbolic links, checked against the new link.
+    Link,
+}
+
+/// An in-memory filesystem for tests and simulations.
+///
+/// Paths are absolute; relative ones are taken from `/`. Everything under a
+/// path passed to `mount` is a separate device, so renames into or out of it
+/// fail with `CrossesDevices` just like between real mounts. `fail` makes an
+/// operation on a path, or anything under it, return an error of the given
+/// kind until `clear_faults` is called.
+pub struct MemoryFs {
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Every path (normalized, with no symlinks in its parent) to its node.
+    /// Hard links are several paths to one node.
+    paths: BTreeMap<PathBuf, u64>,
+    nodes: HashMap<u64, Node>,
+    next_id: u64,
+    mounts: Vec<PathBuf>,
+    faults: Vec<(Op, PathBuf, io::ErrorKind)>,
+}
+
+struct Node {
+    kind: NodeKind,
+    modified: SystemTime,
+    accessed: SystemTime,
+    created: SystemTime,
+}
+
+enum NodeKind {
+    File(Vec<u8>),
+    Dir,
+    Symlink(PathBuf),
+}
+
+/// Symlinks followed before giving up, as Linux does.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+impl Default for MemoryFs {
+    fn default() -> MemoryFs {
+        MemoryFs::new()
+    }
+}
+
+impl MemoryFs {
+    /// An empty filesystem holding only `/`.
+    pub fn new() -> MemoryFs {
+        let mut state = State {
+            paths: BTreeMap::new(),
+            nodes: HashMap::new(),
+            next_id: 1,
+            mounts: Vec::new(),
+            faults: Vec::new(),
+        };
+        state.insert(PathBuf::from("/"), NodeKind::Dir);
+        MemoryFs {
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Create or replace a file with `data`, creating its parent directories.
+    pub fn write_file(&self, path: impl AsRef<Path>, data: impl Into<Vec<u8>>) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        let mut state = self.lock();
+        let path = state.resolve(path, true)?;
+        if let Some(Node {
+            kind: NodeKind::Dir,
+            ..
+        }) = state.node(&path)
+        {
+            return Err(io::ErrorKind::IsADirectory.into());
+        }
+        state.unlink(&path);
+        state.insert(path, NodeKind::File(data.into()));
+        Ok(())
+    }
+
+    /// Create a symlink at `link` pointing to `target`, which may be a
+    /// directory or not exist at all.
+    pub fn symlink(&self, target: impl AsRef<Path>, link: impl AsRef<Path>) -> io::Result<()> {
+        let mut state = self.lock();
+        let link = state.resolve_new(link.as_ref())?;
+        state.insert(link, NodeKind::Symlink(target.as_ref().to_path_buf()));
+        Ok(())
+    }
+
+    /// Set the modification time of `path` (following symlinks).
+    pub fn set_modified(&self, path: impl AsRef<Path>, time: SystemTime) -> io::Result<()> {
+        let mut state = self.lock();
+        let path = state.resolve(path.as_ref(), true)?;
+        state.node_mut(&path)?.modified = time;
+        Ok(())
+    }
+
+    /// Contents of the file at `path`.
+    pub fn read_file(&self, path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.open(path.as_ref())?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Every path in the filesystem, sorted, `/` included.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.lock().paths.keys().cloned().collect()
+    }
+
+    /// Make everything under `path` a separate device.
+    pub fn mount(&self, path: impl AsRef<Path>) {
+        self.lock().mounts.push(normalize(path.as_ref()));
+    }
+
+    /// Make `op` fail with `kind` on `path` and everything under it.
+    pub fn fail(&self, op: Op, path: impl AsRef<Path>, kind: io::ErrorKind) {
+        self.lock()
+            .faults
+            .push((op, normalize(path.as_ref()), kind));
+    }
+
+    pub fn clear_faults(&self) {
+        self.lock().faults.clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Lock the state, failing if a fault is set for `op` on any of `paths`.
+    fn start(&self, op: Op, paths: &[&Path]) -> io::Result<MutexGuard<'_, State>> {
+        let state = self.lock();
+        for path in paths {
+            let path = normalize(path);
+            if let Some((_, _, kind)) = state
+                .faults
+                .iter()
+                .find(|(o, p, _)| *o == op && path.starts_with(p))
+            {
+                return Err(io::Error::new(
+                    *kind,
+                    format!("injected {:?} fault on {:?}", op, path),
+                ));
+            }
+        }
+        Ok(state)
+    }
+}
+
+impl FileSystem for MemoryFs {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let state = self.start(Op::Metadata, &[path])?;
+        let path = state.resolve(path, true)?;
+        state.metadata(&path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let state = self.start(Op::Metadata, &[path])?;
+        let resolved = state.resolve(path, false)?;
+        state.metadata(&resolved)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.start(Op::ReadDir, &[path])?;
+        let dir = state.resolve(path, true)?;
+        match state.node(&dir) {
+            Some(Node {
+                kind: NodeKind::Dir,
+                ..
+            }) => Ok(state
+                .children(&dir)
+                .filter_map(|child| child.file_name().map(|name| path.join(name)))
+                .collect()),
+            Some(_) => Err(io::ErrorKind::NotADirectory.into()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let state = self.start(Op::Canonicalize, &[path])?;
+        let resolved = state.resolve(path, true)?;
+        state.metadata(&resolved)?;
+        Ok(resolved)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        let state = self.start(Op::Metadata, &[path])?;
+        let resolved = state.resolve(path, false)?;
+        match state.node(&resolved).map(|n| &n.kind) {
+            Some(NodeKind::Symlink(target)) => Ok(target.clone()),
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a symlink", path),
+            )),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadSeek>> {
+        let state = self.start(Op::Open, &[path])?;
+        let path = state.resolve(path, true)?;
+        match state.node(&path).map(|n| &n.kind) {
+            Some(NodeKind::File(data)) => Ok(Box::new(Cursor::new(data.clone()))),
+            Some(_) => Err(io::ErrorKind::IsADirectory.into()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.start(Op::CreateDir, &[path])?;
+        let mut prefix = PathBuf::new();
+        for component in normalize(path).components() {
+            prefix.push(component);
+            let resolved = state.resolve(&prefix, true)?;
+            match state.node(&resolved).map(|n| &n.kind) {
+                Some(NodeKind::Dir) => {}
+                Some(_) => return Err(io::ErrorKind::NotADirectory.into()),
+                None => state.insert(resolved, NodeKind::Dir),
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.start(Op::Rename, &[from, to])?;
+        let from = state.resolve(from, false)?;
+        let to = state.resolve_dest(to)?;
+        state.metadata(&from)?;
+        if state.device(&from) != state.device(&to) {
+            return Err(io::ErrorKind::CrossesDevices.into());
+        }
+        if from == to {
+            return Ok(());
+        }
+
+        let from_is_dir = matches!(state.node(&from).map(|n| &n.kind), Some(NodeKind::Dir));
+        match state.node(&to).map(|n| &n.kind) {
+            Some(NodeKind::Dir) if !from_is_dir => return Err(io::ErrorKind::IsADirectory.into()),
+            Some(NodeKind::Dir) if state.children(&to).next().is_some() => {
+                return Err(io::ErrorKind::DirectoryNotEmpty.into());
+            }
+            Some(_) if from_is_dir => return Err(io::ErrorKind::NotADirectory.into()),
+            _ => {}
+        }
+        if from_is_dir && to.starts_with(&from) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot move a directory into itself",
+            ));
+        }
+
+        state.unlink(&to);
+        let moved: Vec<(PathBuf, u64)> = state
+            .paths
+            .range(from.clone()..)
+            .take_while(|(p, _)| p.starts_with(&from))
+            .map(|(p, id)| (p.clone(), *id))
+            .collect();
+        for (old, id) in moved {
+            state.paths.remove(&old);
+            let rest = old.strip_prefix(&from).unwrap_or(Path::new(""));
+            let new = if rest.as_os_str().is_empty() {
+                to.clone()
+            } else {
+                to.join(rest)
+            };
+            state.paths.insert(new, id);
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.start(Op::RemoveFile, &[path])?;
+        let path = state.resolve(path, false)?;
+        match state.node(&path).map(|n| &n.kind) {
+            Some(NodeKind::Dir) => Err(io::ErrorKind::IsADirectory.into()),
+            Some(_) => {
+                state.unlink(&path);
+                Ok(())
+            }
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.start(Op::RemoveDir, &[path])?;
+        let path = state.resolve(path, false)?;
+        match state.node(&path).map(|n| &n.kind) {
+            Some(NodeKind::Dir) if path == Path::new("/") => {
+                Err(io::ErrorKind::PermissionDenied.into())
+            }
+            Some(NodeKind::Dir) if state.children(&path).next().is_some() => {
+                Err(io::ErrorKind::DirectoryNotEmpty.into())
+            }
+            Some(NodeKind::Dir) => {
+                state.unlink(&path);
+                Ok(())
+            }
+            Some(_) => Err(io::ErrorKind::NotADirectory.into()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn copy_file(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let mut state = self.start(Op::Copy, &[src, dst])?;
+        let src = state.resolve(src, true)?;
+        let (data, modified, accessed) = match state.node(&src) {
+            Some(Node {
+                kind: NodeKind::File(data),
+                modified,
+                accessed,
+                ..
+            }) => (data.clone(), *modified, *accessed),
+            Some(_) => return Err(io::ErrorKind::IsADirectory.into()),
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        let dst = state.resolve_new(dst)?;
+        state.insert(dst.clone(), NodeKind::File(data));
+        let node = state.node_mut(&dst)?;
+        node.modified = modified;
+        node.accessed = accessed;
+        Ok(())
+    }
+
+    fn reflink(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        {
+            let state = self.start(Op::Copy, &[src, dst])?;
+            let from = state.resolve(src, true)?;
+            let to = state.resolve_dest(dst)?;
+            if state.device(&from) != state.device(&to) {
+                return Err(io::ErrorKind::CrossesDevices.into());
+            }
+        }
+        // A clone only differs from a copy in how it is stored.
+        self.copy_file(src, dst)
+    }
+
+    fn append(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut state = self.start(Op::Append, &[path])?;
+        let path = state.resolve_dest(path)?;
+        if state.node(&path).is_none() {
+            state.insert(path.clone(), NodeKind::File(Vec::new()));
+        }
+        let node = state.node_mut(&path)?;
+        match &mut node.kind {
+            NodeKind::File(contents) => contents.extend_from_slice(data),
+            _ => return Err(io::ErrorKind::IsADirectory.into()),
+        }
+        node.modified = SystemTime::now();
+        Ok(())
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        let mut state = self.start(Op::Link, &[link])?;
+        let original = state.resolve(original, false)?;
+        let Some(&id) = state.paths.get(&original) else {
+            return Err(io::ErrorKind::NotFound.into());
+        };
+        if matches!(state.nodes[&id].kind, NodeKind::Dir) {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        let link = state.resolve_new(link)?;
+        if state.device(&original) != state.device(&link) {
+            return Err(io::ErrorKind::CrossesDevices.into());
+        }
+        state.paths.insert(link, id);
+        Ok(())
+    }
+
+    fn symlink_file(&self, target: &Path, link: &Path) -> io::Result<()> {
+        let mut state = self.start(Op::Link, &[link])?;
+        let link = state.resolve_new(link)?;
+        state.insert(link, NodeKind::Symlink(target.to_path_buf()));
+        Ok(())
+    }
+}
+
+impl State {
+    fn node(&self, path: &Path) -> Option<&Node> {
+        self.paths.get(path).map(|id| &self.nodes[id])
+    }
+
+    fn node_mut(&mut self, path: &Path) -> io::Result<&mut Node> {
+        let id = self.paths.get(path).ok_or(io::ErrorKind::NotFound)?;
+        Ok(self.nodes.get_mut(id).expect("path points at a live node"))
+    }
+
+    fn insert(&mut self, path: PathBuf, kind: NodeKind) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let now = SystemTime::now();
+        self.nodes.insert(
+            id,
+            Node {
+                kind,
+                modified: now,
+                accessed: now,
+                created: now,
+            },
+        );
+        self.paths.insert(path, id);
+    }
+
+    /// Remove `path`, and its node once no other path links to it.
+    fn unlink(&mut self, path: &Path) {
+        if let Some(id) = self.paths.remove(path)
+            && !self.paths.values().any(|other| *other == id)
+        {
+            self.nodes.remove(&id);
+        }
+    }
+
+    fn children<'a>(&'a self, dir: &'a Path) -> impl Iterator<Item = &'a PathBuf> + 'a {
+        self.paths
+            .keys()
+            .filter(move |p| p.parent() == Some(dir) && p.as_path() != dir)
+    }
+
+    /// Mount index (0 for the root device) of the mount `path` is under.
+    fn device(&self, path: &Path) -> u64 {
+        self.mounts
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| path.starts_with(m))
+            .max_by_key(|(_, m)| m.components().count())
+            .map_or(0, |(i, _)| i as u64 + 1)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let id = *self.paths.get(path).ok_or(io::ErrorKind::NotFound)?;
+        let node = &self.nodes[&id];
+        let (kind, len) = match &node.kind {
+            NodeKind::File(data) => (FileKind::File, data.len() as u64),
+            NodeKind::Dir => (FileKind::Dir, 0),
+            NodeKind::Symlink(target) => (FileKind::Symlink, target.as_os_str().len() as u64),
+        };
+        Ok(Metadata {
+            kind,
+            len,
+            modified: Some(node.modified),
+            accessed: Some(node.accessed),
+            created: Some(node.created),
+            file_id: Some((self.device(path), id)),
+        })
+    }
+
+    /// Where `path` leads, following symlinks in its parents and, with
+    /// `follow_last`, in its final component too. Only the parents have to
+    /// exist.
+    fn resolve(&self, path: &Path, follow_last: bool) -> io::Result<PathBuf> {
+        let mut todo: VecDeque<OsString> = components(&normalize(path));
+        let mut current = PathBuf::from("/");
+        let mut hops = 0;
+
+        while let Some(name) = todo.pop_front() {
+            if name == ".." {
+                current.pop();
+                continue;
+            }
+            let next = current.join(&name);
+            let last = todo.is_empty();
+            match self.node(&next).map(|n| &n.kind) {
+                Some(NodeKind::Symlink(target)) if !last || follow_last => {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return Err(io::Error::other(format!(
+                            "too many levels of symbolic links in {:?}",
+                            path
+                        )));
+                    }
+                    if target.is_absolute() {
+                        current = PathBuf::from("/");
+                    }
+                    let mut expanded = components(target);
+                    expanded.extend(todo);
+                    todo = expanded;
+                }
+                Some(NodeKind::Dir) => current = next,
+                Some(_) if !last => return Err(io::ErrorKind::NotADirectory.into()),
+                None if !last => return Err(io::ErrorKind::NotFound.into()),
+                _ => current = next,
+            }
+        }
+        Ok(current)
+    }
+
+    /// Resolve a path about to be written: its parent must be a directory.
+    fn resolve_dest(&self, path: &Path) -> io::Result<PathBuf> {
+        let resolved = self.resolve(path, false)?;
+        match resolved
+            .parent()
+            .and_then(|p| self.node(p))
+            .map(|n| &n.kind)
+        {
+            Some(NodeKind::Dir) => Ok(resolved),
+            Some(_) => Err(io::ErrorKind::NotADirectory.into()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Like `resolve_dest`, but nothing may exist at the path yet.
+    fn resolve_new(&self, path: &Path) -> io::Result<PathBuf> {
+        let resolved = self.resolve_dest(path)?;
+        if self.paths.contains_key(&resolved) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        Ok(resolved)
+    }
+}
+
+/// Absolute form of `path` with `.` dropped; `..` is left for `resolve`,
+/// which has to apply it after following symlinks.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => out.push(name),
+            Component::ParentDir => out.push(".."),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    out
+}
+
+fn components(path: &Path) -> VecDeque<OsString> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rename_across_a_mount_crosses_devices() {
+        let fs = MemoryFs::new();
+        fs.write_file("/home/a.txt", "a").unwrap();
+        fs.create_dir_all(Path::new("/mnt/usb")).unwrap();
+        fs.mount("/mnt/usb");
+
+        let err = fs
+            .rename(Path::new("/home/a.txt"), Path::new("/mnt/usb/a.txt"))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::CrossesDevices);
+        assert_eq!(fs.read_file("/home/a.txt").unwrap(), b"a");
+
+        fs.rename(Path::new("/home/a.txt"), Path::new("/home/b.txt"))
+            .unwrap();
+        assert!(fs.symlink_metadata(Path::new("/home/b.txt")).is_ok());
+    }
+
+    #[test]
+    fn faults_apply_below_their_path_until_cleared() {
+        let fs = MemoryFs::new();
+        fs.write_file("/data/sub/a.txt", "a").unwrap();
+        fs.write_file("/other/b.txt", "b").unwrap();
+        fs.fail(Op::Open, "/data", io::ErrorKind::PermissionDenied);
+
+        let err = fs.open(Path::new("/data/sub/a.txt")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(fs.open(Path::new("/other/b.txt")).is_ok());
+        // Only the faulted operation fails.
+        assert!(fs.metadata(Path::new("/data/sub/a.txt")).is_ok());
+
+        fs.clear_faults();
+        assert_eq!(fs.read_file("/data/sub/a.txt").unwrap(), b"a");
+    }
+
+    #[test]
+    fn hard_links_share_a_node_until_the_last_is_removed() {
+        let fs = MemoryFs::new();
+        fs.write_file("/a.txt", "data").unwrap();
+        fs.hard_link(Path::new("/a.txt"), Path::new("/b.txt"))
+            .unwrap();
+        let id = |p: &str| fs.metadata(Path::new(p)).unwrap().file_id;
+        assert_eq!(id("/a.txt"), id("/b.txt"));
+
+        fs.remove_file(Path::new("/a.txt")).unwrap();
+        assert_eq!(fs.read_file("/b.txt").unwrap(), b"data");
+        assert_eq!(fs.lock().nodes.len(), 2, "root and the linked file");
+
+        fs.remove_file(Path::new("/b.txt")).unwrap();
+        assert_eq!(fs.lock().nodes.len(), 1, "only the root is left");
+    }
+
+    #[test]
+    fn symlinks_resolve_in_parents_and_targets() {
+        let fs = MemoryFs::new();
+        fs.write_file("/real/dir/f.txt", "f").unwrap();
+        fs.symlink("/real/dir", "/abs").unwrap();
+        fs.symlink("real/dir", "/rel").unwrap();
+        fs.symlink("f.txt", "/real/dir/link.txt").unwrap();
+
+        assert_eq!(fs.read_file("/abs/f.txt").unwrap(), b"f");
+        assert_eq!(fs.read_file("/rel/link.txt").unwrap(), b"f");
+        assert_eq!(
+            fs.canonicalize(Path::new("/abs/link.txt")).unwrap(),
+            Path::new("/real/dir/f.txt")
+        );
+        let own = fs.symlink_metadata(Path::new("/abs/link.txt")).unwrap();
+        assert!(own.is_symlink());
+        assert_eq!(
+            fs.read_link(Path::new("/abs/link.txt")).unwrap(),
+            Path::new("f.txt")
+        );
+    }
+
+    #[test]
+    fn symlink_loops_give_up() {
+        let fs = MemoryFs::new();
+        fs.symlink("/b", "/a").unwrap();
+        fs.symlink("/a", "/b").unwrap();
+        fs.symlink("/self", "/self").unwrap();
+
+        for path in ["/a", "/self", "/a/x"] {
+            let err = fs.metadata(Path::new(path)).unwrap_err();
+            assert!(err.to_string().contains("too many levels"), "{}", err);
+        }
+        // The link itself can still be looked at.
+        assert!(fs.symlink_metadata(Path::new("/a")).unwrap().is_symlink());
+    }
+
+    #[test]
+    fn a_chain_within_the_hop_limit_resolves() {
+        let fs = MemoryFs::new();
+        fs.write_file("/end.txt", "end").unwrap();
+        let mut target = PathBuf::from("/end.txt");
+        for n in 0..MAX_SYMLINK_HOPS {
+            let link = PathBuf::from(format!("/l{}", n));
+            fs.symlink(&target, &link).unwrap();
+            target = link;
+        }
+        assert_eq!(fs.read_file(&target).unwrap(), b"end");
+
+        fs.symlink(&target, "/one-too-many").unwrap();
+        assert!(fs.metadata(Path::new("/one-too-many")).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+// Noticing files as they arrive in a folder.
+//
+// A `Watcher` subscribes to inotify events for the top level of a folder and
+// hands back files once they look finished: nothing has touched them for the
+// settle time and their size and mtime have held still since the last look.
+// Browsers and download managers write into a `.part` or `.crdownload` file
+// and rename it when done, so those are never handed back; the rename is.
+// Some create an empty file under the final name first, which is held back
+// for as long as its `.part` or `.crdownload` is there.
+
+use crate::vfs::{FileSystem, RealFs};
+use inotify::{EventMask, Inotify, WatchMask};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Suffixes of files that are still being downloaded.
+pub const PARTIAL_SUFFIXES: &[&str] = &[".part", ".crdownload"];
+
+/// How often to check for events and settled files.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub struct Watcher {
+    inotify: Inotify,
+    folder: PathBuf,
+    settle: Duration,
+    /// Files seen but not yet handed back.
+    pending: HashMap<PathBuf, Pending>,
+    buffer: Vec<u8>,
+}
+
+struct Pending {
+    /// Size and mtime at the last look, `None` before the first.
+    seen: Option<(u64, Option<SystemTime>)>,
+    /// Last event for the file or change in what it looked like.
+    changed: Instant,
+}
+
+impl Watcher {
+    /// Start watching `folder`. Files already in it are treated as if they
+    /// had just arrived.
+    pub fn new(folder: &Path, settle: Duration) -> io::Result<Watcher> {
+        let inotify = Inotify::init()?;
+        inotify.watches().add(
+            folder,
+            WatchMask::CREATE
+                | WatchMask::MODIFY
+                | WatchMask::ATTRIB
+                | WatchMask::CLOSE_WRITE
+                | WatchMask::MOVED_TO
+                | WatchMask::MOVED_FROM
+                | WatchMask::DELETE
+                | WatchMask::ONLYDIR,
+        )?;
+        let mut watcher = Watcher {
+            inotify,
+            folder: folder.to_path_buf(),
+            settle,
+            pending: HashMap::new(),
+            buffer: vec![0; 4096],
+        };
+        watcher.queue_existing()?;
+        Ok(watcher)
+    }
+
+    /// Block until at least one file has settled and return them all.
+    pub fn wait(&mut self) -> io::Result<Vec<PathBuf>> {
+        loop {
+            self.read_events()?;
+            let ready = self.settled();
+            if !ready.is_empty() {
+                return Ok(ready);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn read_events(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        loop {
+            let events = match self.inotify.read_events(&mut self.buffer) {
+                Ok(events) => events,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let mut overflowed = false;
+            for event in events {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    overflowed = true;
+                    continue;
+                }
+                if event.mask.contains(EventMask::ISDIR) {
+                    continue;
+                }
+                let Some(name) = event.name else { continue };
+                let path = self.folder.join(name);
+                if event
+                    .mask
+                    .intersects(EventMask::DELETE | EventMask::MOVED_FROM)
+                {
+                    self.pending.remove(&path);
+                } else if !is_partial(&path) {
+                    touch(&mut self.pending, path, now);
+                }
+            }
+            // Events were dropped; look at everything again.
+            if overflowed {
+                self.queue_existing()?;
+            }
+        }
+    }
+
+    fn queue_existing(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        for path in RealFs.read_dir(&self.folder)? {
+            if !is_partial(&path) {
+                touch(&mut self.pending, path, now);
+            }
+        }
+        Ok(())
+    }
+
+    /// Take the pending files that have held still for the settle time.
+    /// Ones that are gone or aren't files are dropped.
+    fn settled(&mut self) -> Vec<PathBuf> {
+        let now = Instant::now();
+        let mut ready = Vec::new();
+        self.pending.retain(|path, pending| {
+            let meta = match RealFs.metadata(path) {
+                Ok(meta) if meta.is_file() => meta,
+                _ => return false,
+            };
+            if being_downloaded(&RealFs, path) {
+                pending.changed = now;
+                return true;
+            }
+            let looks = Some((meta.len, meta.modified));
+            if pending.seen != looks {
+                pending.seen = looks;
+                pending.changed = now;
+                return true;
+            }
+            if now.duration_since(pending.changed) < self.settle {
+                return true;
+            }
+            ready.push(path.clone());
+            false
+        });
+        ready.sort();
+        ready
+    }
+}
+
+/// Note activity on `path`, restarting its settle time.
+fn touch(pending: &mut HashMap<PathBuf, Pending>, path: PathBuf, now: Instant) {
+    pending
+        .entry(path)
+        .and_modify(|p| p.changed = now)
+        .or_insert(Pending {
+            seen: None,
+            changed: now,
+        });
+}
+
+/// Whether `path` is the placeholder for a download still being written
+/// beside it.
+fn being_downloaded(fs: &dyn FileSystem, path: &Path) -> bool {
+    PARTIAL_SUFFIXES.iter().any(|suffix| {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(suffix);
+        fs.symlink_metadata(Path::new(&partial)).is_ok()
+    })
+}
+
+/// Whether `path` is a download still in progress.
+pub fn is_partial(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    PARTIAL_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryFs;
+
+    #[test]
+    fn placeholders_wait_for_their_download() {
+        let fs = MemoryFs::new();
+        fs.write_file("/dl/movie.mkv", "").unwrap();
+        fs.write_file("/dl/movie.mkv.crdownload", "partial")
+            .unwrap();
+        fs.write_file("/dl/notes.txt", "done").unwrap();
+
+        assert!(being_downloaded(&fs, Path::new("/dl/movie.mkv")));
+        assert!(!being_downloaded(&fs, Path::new("/dl/notes.txt")));
+
+        fs.rename(
+            Path::new("/dl/movie.mkv.crdownload"),
+            Path::new("/dl/movie.mkv"),
+        )
+        .unwrap();
+        assert!(!being_downloaded(&fs, Path::new("/dl/movie.mkv")));
+    }
+}